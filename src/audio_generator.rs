@@ -1,6 +1,11 @@
 
-use crate::midi_parser::{MidiFile, MidiError, Format, Division, Event, MidiEvent, MetaEvent, ControllerMessage, MidiErrorType};
+use crate::midi_parser::{MidiFile, MidiError, Event, MidiEvent, MidiErrorType, SysexMessage};
 use raylib::get_random_value;
+use crate::tempo_map::TempoMap;
+use crate::ports::{PortTracker, ChannelId, CHANNEL_COUNT};
+use crate::channel_state::{ChannelStates, ChannelState, ChannelTimeline};
+use crate::patches::{Patch, SoundSet, InstrumentFamily};
+use crate::notes::NoteOverlap;
 use std::sync::{Arc, Mutex};
 
 const NOTE_FREQUENCIES: [f64;128] = [8.175798915643682, 
@@ -140,16 +145,18 @@ use hound;
 use std::f64::consts::PI;
 use std::i16;
 
-const STANDARD_TEMPO: u32 = 400_000;
+const PRESSURE_DEPTH: f64 = 0.5; // Maximum amplitude boost caused by aftertouch
 
 
-fn note_sine(t: f64, note: usize) -> f64 {
-    (t * NOTE_FREQUENCIES[note] * 2.0 * PI).sin()
+// The wave forms get the time since the key was pressed and the phase of the note in periods.
+// The phase is accumulated sample by sample, so that the pitch can be bent while a note is held.
+fn note_sine(_: f64, phase: f64) -> f64 {
+    (phase * 2.0 * PI).sin()
 }
 
-fn note_square(t: f64, note: usize) -> f64 {
+fn note_square(_: f64, phase: f64) -> f64 {
     let period_length = 1.0;
-    let period_progress = t*NOTE_FREQUENCIES[note] % period_length;
+    let period_progress = phase % period_length;
 
     const DEFAULT_VOLUME: f64 = 0.25;
     if period_progress >= period_length / 2.0 {
@@ -159,7 +166,7 @@ fn note_square(t: f64, note: usize) -> f64 {
     }
 }
 
-fn note_drum(t: f64, _: usize) -> f64 {
+fn note_drum(t: f64, _: f64) -> f64 {
     let noise = get_random_value::<i32>(-i16::MAX as i32, i16::MAX as i32) as f64 / i16::MAX as f64;
     if t < 0.15 && t >= 0.0 {
         noise * (1.0-t/0.15)
@@ -168,221 +175,204 @@ fn note_drum(t: f64, _: usize) -> f64 {
     }
 }
 
-fn note_saw_tooth(t: f64, note: usize) -> f64 {
+fn note_saw_tooth(_: f64, phase: f64) -> f64 {
     let period_length = 1.0;
-    let period_progress = t*NOTE_FREQUENCIES[note] % period_length;
+    let period_progress = phase % period_length;
     (period_progress / period_length * 2.0 - 1.0) * 0.25
 }
 
 #[derive(Clone,Copy,PartialEq,Debug)]
 struct PressedKeyInfo {
     elapsed_time: f64,
-    channel: u8,
+    phase: f64,
+    channel: ChannelId,
     key: u8,
     velocity: u8,
+    pressure: u8,
 }
 
-#[derive(Debug,Clone)]
-pub enum NormalizedEvent {
-    KeyOn { key: u8, program: u8, channel: u8 },
-    KeyOff { key: u8, program: u8, channel: u8 }
+fn pressure_gain(pressure: u8) -> f64 {
+    1.0 + PRESSURE_DEPTH * pressure as f64 / 127.0
 }
 
-#[derive(Debug, Clone)]
-pub struct NormalizedTrack {
-    pub events: Vec<(f64, NormalizedEvent)>,
+// Values of a channel that are needed for every sample, taken from its state whenever it changes
+#[derive(Clone,Copy,PartialEq,Debug)]
+struct ChannelSound {
+    family: InstrumentFamily,
+    gain: f64, // Volume, expression and channel pressure
+    pitch_factor: f64, // Frequency factor of the pitch wheel and the tuning
+}
+
+impl ChannelSound {
+    fn new(channel: ChannelId, state: &ChannelState, sound_set: SoundSet) -> Self {
+        Self {
+            family: Patch::new(channel.channel, state, sound_set).family(),
+            gain: state.volume() as f64 / 16383.0 * state.expression() as f64 / 16383.0 * pressure_gain(state.pressure),
+            pitch_factor: 2f64.powf(state.pitch_offset() / 12.0),
+        }
+    }
 }
 
-impl NormalizedTrack {
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub struct RenderOptions {
+    pub note_overlap: NoteOverlap, // Which of several held notes of a key is released by a note off
+    pub start_seconds: f64, // Position of the file at which the audio starts
+}
+
+impl RenderOptions {
     pub fn new() -> Self {
-        Self { events: Vec::new() }
+        Self { note_overlap: NoteOverlap::Fifo, start_seconds: 0.0 }
     }
 }
 
 #[derive(Clone,Debug)]
 pub struct ProgressInfo {
-    pub track: usize,
-    pub track_progress: f64,
-    pub result: Option<Vec<NormalizedTrack>>,
+    pub progress: f64,
+    pub finished: bool,
     pub error: Option<MidiError>,
 }
 
 impl ProgressInfo {
     pub fn new() -> Self {
-        Self { track: 0, track_progress: 0.0, result: None, error: None }
+        Self { progress: 0.0, finished: false, error: None }
     }
 }
 
-pub fn generate_audio(file: Arc<MidiFile>, wav_file_path: &str, progress_info: Arc<Mutex<ProgressInfo>>) {
+pub fn generate_audio(file: Arc<MidiFile>, wav_file_path: &str, options: RenderOptions, progress_info: Arc<Mutex<ProgressInfo>>) {
     let spec = hound::WavSpec {
         channels: 1,
         sample_rate: 44100,
         bits_per_sample: 16,
         sample_format: hound::SampleFormat::Int
     };
-    let mut writer = hound::WavWriter::create(wav_file_path, spec).unwrap();
-
-    let simult_tracks: usize = file.get_simult_track_count();
+    let mut writer = match hound::WavWriter::create(wav_file_path, spec) {
+        Ok(writer) => writer,
+        Err(e) => {
+            let mut pi = progress_info.lock().unwrap();
+            pi.error = Some(MidiError::new(MidiErrorType::IO, e.to_string()));
+            return;
+        }
+    };
 
-    assert!(file.header.format != Format::SequenceTrack);
+    let total_events: usize = file.tracks.iter().map(|track| track.events.len()).sum();
+    let tempo_map = TempoMap::new(&file);
+    let sec_per_sample = 1.0 / spec.sample_rate as f64;
 
-    let mut sample_buffer = Vec::<i16>::new();
-    let mut tempo_changes = Vec::new();
+    // The first tick that is played. The channel states before it are looked up instead of being replayed.
+    let start_tick = tempo_map.first_tick_at(options.start_seconds);
 
-    let mut tempo = STANDARD_TEMPO;
-    let mut usec_per_tick = if let Division::TicksPerQuarter(ticks) = file.header.division {
-        tempo / ticks
-    } else {
-        todo!("Other divisions")
+    let mut ports = PortTracker::new(&file);
+    let mut states = match start_tick {
+        0 => ChannelStates::new(),
+        _ => ChannelTimeline::new(&file).states_at_seconds(options.start_seconds),
     };
+    let sound_set = SoundSet::detect(&file);
+    let mut sounds = (0..CHANNEL_COUNT).map(|index| {
+        ChannelSound::new(ChannelId::new((index / 16) as u8, (index % 16) as u8), &ChannelState::new(), sound_set)
+    }).collect::<Vec<_>>(); // Per port and channel
+    for (channel, state) in states.iter() {
+        sounds[channel.index()] = ChannelSound::new(*channel, state, sound_set);
+    }
+    let mut pressed_keys = Vec::<PressedKeyInfo>::new();
+    let mut sample_pointer = 0;
+    let mut master_volume = 1.0;
 
-    let mut normalized_tracks = vec![NormalizedTrack::new(); simult_tracks];
-
-    for track in 0..simult_tracks {
+    // All tracks are rendered in a single pass over the merged events
+    for (event_index, (tick, track, event)) in file.merged_events().enumerate() {
         {
             let mut pi = progress_info.lock().unwrap();
-            pi.track = track;
+            pi.progress = event_index as f64 / total_events as f64;
         }
-        let mut channels = [(0,127);256];
-        let mut pressed_keys = Vec::<PressedKeyInfo>::new();
-        let mut sample_pointer = 0;
 
-        for (sample_index, usecs) in tempo_changes.iter() {
-            if sample_pointer == *sample_index {
-                usec_per_tick = *usecs;
+        let event_seconds = tempo_map.ticks_to_seconds(tick);
+        let event_sample = ((event_seconds - options.start_seconds).max(0.0) * spec.sample_rate as f64).round() as usize;
+
+        while sample_pointer < event_sample {
+            let mut s = 0.0;
+            for key_info in pressed_keys.iter_mut() {
+                    let sound = sounds[key_info.channel.index()];
+                    let note_function = match sound.family {
+                        InstrumentFamily::Piano | InstrumentFamily::ChromaticPercussion | InstrumentFamily::Organ | InstrumentFamily::Guitar => note_sine,
+                        InstrumentFamily::SynthPad | InstrumentFamily::Ethnic => note_sine,
+                        InstrumentFamily::Bass | InstrumentFamily::Brass | InstrumentFamily::Reed => note_saw_tooth,
+                        InstrumentFamily::Strings | InstrumentFamily::Ensemble | InstrumentFamily::Pipe | InstrumentFamily::SynthLead => note_square,
+                        InstrumentFamily::SynthEffects | InstrumentFamily::Percussive | InstrumentFamily::SoundEffects | InstrumentFamily::Drums => note_drum,
+                    };
+                    
+                    let gain = pressure_gain(key_info.pressure) * sound.gain;
+                    s += note_function(key_info.elapsed_time, key_info.phase) * key_info.velocity as f64 / 127.0 * gain;
+                    key_info.elapsed_time += sec_per_sample;
+                    key_info.phase += NOTE_FREQUENCIES[key_info.key as usize & 0x7F] * sound.pitch_factor * sec_per_sample;
             }
-        }
-
-        for (event_index, (dt,event)) in file.tracks[track].events.iter().enumerate() {
-            {
+            s *= master_volume;
+            s /= 10.0;
+            if s.abs() >= 1.0 {
+                s = s / s.abs();
+            }
+            let sample_value = (i16::MAX as f64 * s) as i16;
+            if let Err(e) = writer.write_sample(sample_value) {
                 let mut pi = progress_info.lock().unwrap();
-                pi.track_progress = event_index as f64 / file.tracks[track].events.len() as f64;
+                pi.error = Some(MidiError::new(MidiErrorType::IO, e.to_string()));
+                return;
             }
-            
-            let sec_per_sample = 1.0 / spec.sample_rate as f64;
-            let mut dt_float = *dt as f64;
-            let mut tick_per_sample = 1_000_000.0 as f64 / usec_per_tick as f64 / spec.sample_rate as f64;
+            sample_pointer += 1;
+        }
 
-            while dt_float > tick_per_sample {
-                dt_float -= tick_per_sample;
-                let mut s = 0.0;
-                for key_info in pressed_keys.iter_mut() {
-                        let program = channels[key_info.channel as usize].0;
-                        let note_function = if program <= 7 { // piano
-                            note_sine
-                        } else if 8 <= program && program <= 15 { // Chromatic Percussion
-                            note_sine
-                        } else if 16 <= program && program <= 23 { // Organ
-                            note_sine
-                        } else if 24 <= program && program <= 31 { // Guitar
-                            note_sine
-                        } else if 32 <= program && program <= 39 { // Bass
-                            note_saw_tooth
-                        } else if 40 <= program && program <= 47 { // Strings
-                            note_square
-                        } else if 48 <= program && program <= 55 { // Ensemble
-                            note_square
-                        } else if 56 <= program && program <= 63 { // Brass
-                            note_saw_tooth
-                        } else if 64 <= program && program <= 71 { // Reed
-                            note_saw_tooth
-                        } else if 72 <= program && program <= 79 { // Pipe
-                            note_square
-                        } else if 80 <= program && program <= 87 { // Synth Lead
-                            note_square
-                        } else if 88 <= program && program <= 95 { // Synth Pad
-                            note_sine
-                        } else if 96 <= program && program <= 103 { // Synth Effects
-                            note_drum
-                        } else if 104 <= program && program <= 111 { // Ethnic
-                            note_sine
-                        } else if 112 <= program && program <= 119 { // Percussive
-                            note_drum
-                        } else if 120 <= program && program <= 127 { // Sound Effects
-                            note_drum
-                        } else {
-                            unreachable!()
-                        };
-                        
-                        s += note_function(key_info.elapsed_time, key_info.key as usize) * channels[key_info.channel as usize].1 as f64 / 127.0 * key_info.velocity as f64 / 127.0;
-                        key_info.elapsed_time += sec_per_sample;
-                }
-                s /= 10.0;
-                if s.abs() >= 1.0 {
-                    s = s / s.abs();
-                }
-                let sample_value = (i16::MAX as f64 * s) as i16;
-                if sample_pointer >= sample_buffer.len() {
-                    sample_buffer.push(sample_value);
-                } else {
-                    if sample_buffer[sample_pointer] == 0 {
-                        sample_buffer[sample_pointer] = sample_value;
-                    } else if (sample_buffer[sample_pointer] as i64 + sample_value as i64).abs() >= i16::MAX as i64 {
-                        sample_buffer[sample_pointer] = sample_buffer[sample_pointer] / sample_buffer[sample_pointer].abs() * i16::MAX;
-                    } else {
-                        sample_buffer[sample_pointer] += sample_value;
-                    }
+        let channel = ports.update(track, event).unwrap_or_default();
+        // Keys that are pressed before the start are still followed, so that they sound on from the start
+        let before_start = tick < start_tick;
+        if !before_start {
+            states.update(ports.port(track), event);
+        }
+        match event {
+            Event::Midi(_, MidiEvent::NoteOff { key, .. }) | Event::Midi(_, MidiEvent::NoteOn { key, velocity: 0 }) => {
+                let is_released = |key_info: &PressedKeyInfo| key_info.channel == channel && key_info.key == *key;
+                let released = match options.note_overlap {
+                    NoteOverlap::Fifo => pressed_keys.iter().position(is_released),
+                    NoteOverlap::Lifo => pressed_keys.iter().rposition(is_released),
+                };
+                if let Some(index) = released {
+                    pressed_keys.remove(index);
                 }
-                for (sample_index, usecs) in tempo_changes.iter() {
-                    if sample_pointer == *sample_index {
-                        usec_per_tick = *usecs;
-                        tick_per_sample = 1_000_000.0 as f64 / usec_per_tick as f64 / spec.sample_rate as f64;
+            },
+            Event::Midi(_, MidiEvent::NoteOn { key, velocity }) => {
+                let elapsed_time = (options.start_seconds - event_seconds).max(0.0);
+                pressed_keys.push(PressedKeyInfo { elapsed_time, phase: 0.0, channel, key: *key, velocity: *velocity, pressure: 0 });
+            }
+            Event::Midi(_, MidiEvent::PolyphonicKeyPressure { key, pressure }) => {
+                for key_info in pressed_keys.iter_mut() {
+                    if key_info.channel == channel && key_info.key == *key {
+                        key_info.pressure = *pressure;
                     }
                 }
-                sample_pointer += 1;
             }
-            
-            
-
-            match event {
-                Event::Meta(MetaEvent::SetTempo { tempo: t }) => {
-                    tempo = *t;
-                    usec_per_tick = if let Division::TicksPerQuarter(ticks) = file.header.division {
-                        tempo / ticks
-                    } else {
-                        todo!("Other divisions")
-                    };
-                    tempo_changes.push((sample_pointer, usec_per_tick));
-                },
-                Event::Midi(c,MidiEvent::NoteOff { key, .. }) | Event::Midi(c, MidiEvent::NoteOn { key, velocity: 0 }) => {
-                    for i in 0..pressed_keys.len() {
-                        if pressed_keys[i].channel == *c && pressed_keys[i].key == *key {
-                            pressed_keys.remove(i);
-                            break;
+            Event::Midi(..) if !before_start => {
+                sounds[channel.index()] = ChannelSound::new(channel, states.get(channel), sound_set);
+            }
+            Event::Sysex(data) => {
+                match SysexMessage::parse(data) {
+                    SysexMessage::GmSystemOn | SysexMessage::GmSystemOff | SysexMessage::Gm2SystemOn | SysexMessage::GsReset | SysexMessage::XgSystemOn if !before_start => {
+                        // Only the device on the port of the track is reset
+                        for (channel, state) in states.port(ports.port(track)) {
+                            sounds[channel.index()] = ChannelSound::new(channel, state, sound_set);
                         }
-                    }
-                    normalized_tracks[track].events.push((sample_pointer as f64 * sec_per_sample, NormalizedEvent::KeyOff { key: *key, program: channels[*c as usize].0, channel: *c }));
-                },
-                Event::Midi(c,MidiEvent::NoteOn { key, velocity }) => {
-                    pressed_keys.push(PressedKeyInfo { elapsed_time: 0.0, channel: *c, key: *key, velocity: *velocity });
-                    normalized_tracks[track].events.push((sample_pointer as f64 * sec_per_sample, NormalizedEvent::KeyOn { key: *key, program: channels[*c as usize].0, channel: *c }));
+                    },
+                    SysexMessage::MasterVolume(volume) => {
+                        master_volume = volume as f64 / 16383.0;
+                    },
+                    _ => {}
                 }
-                
-                Event::Midi(c, MidiEvent::ControlChange(ControllerMessage::ChannelVolumeMSB(new_volume))) => {
-                    channels[*c as usize].1 = *new_volume;
-                },
-                Event::Midi(c, MidiEvent::ProgramChange(prog)) => {
-                    channels[*c as usize].0 = *prog;
-                }
-                _ => {}
             }
-        }
-    }
-
-    for s in sample_buffer {
-        if let Err(e) = writer.write_sample(s) {
-            let mut pi = progress_info.lock().unwrap();
-            pi.error = Some(MidiError { message: e.to_string(), error_type: MidiErrorType::IO });
-            return;
+            _ => {}
         }
     }
 
     if let Err(e) = writer.finalize() {
         let mut pi = progress_info.lock().unwrap();
-        pi.error = Some(MidiError { message: e.to_string(), error_type: MidiErrorType::IO });
+        pi.error = Some(MidiError::new(MidiErrorType::IO, e.to_string()));
         return;
     }
 
     let mut pi = progress_info.lock().unwrap();
-    pi.result = Some(normalized_tracks);
+    pi.finished = true;
 }
\ No newline at end of file
@@ -0,0 +1,262 @@
+
+use std::collections::BTreeMap;
+
+use crate::midi_parser::{MidiFile, Event, MidiEvent, ControllerMessage, ControllerValues, SysexMessage};
+use crate::parameters::{ParameterState, ParameterChange, CENTER};
+use crate::ports::{PortTracker, ChannelId};
+use crate::tempo_map::TempoMap;
+
+const SNAPSHOT_INTERVAL: usize = 256; // Channel events between two snapshots of the timeline
+
+// Controllers that keep their value on Reset All Controllers (GM recommended practice RP-015)
+const KEPT_ON_RESET: [u8; 11] = [0x00, 0x20, 0x07, 0x27, 0x0A, 0x2A, 0x5B, 0x5C, 0x5D, 0x5E, 0x5F];
+
+// Everything that is known about a channel at a point of time
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelState {
+    pub program: u8,
+    pub bank: (u8, u8), // (MSB, LSB) that was selected when the program changed
+    pub pitch_wheel: u32, // 0-16383, centered at 8192
+    pub pressure: u8,
+    pub controllers: ControllerValues,
+    pub parameters: ParameterState,
+}
+
+impl ChannelState {
+    // State after a GM System On
+    pub fn new() -> Self {
+        let mut controllers = ControllerValues::new();
+        for message in [ControllerMessage::ChannelVolumeMSB(100), ControllerMessage::PanMSB(64), ControllerMessage::ExpressionControllerMSB(127)] {
+            controllers.update(&message);
+        }
+        Self { program: 0, bank: (0, 0), pitch_wheel: CENTER as u32, pressure: 0, controllers, parameters: ParameterState::new() }
+    }
+
+    // Applies an event of this channel. Returns the parameter change for RPN/NRPN data entries.
+    pub fn update(&mut self, event: &MidiEvent) -> Option<ParameterChange> {
+        match event {
+            MidiEvent::ControlChange(message @ ControllerMessage::ResetAllControllers(_)) => {
+                let mut controllers = ChannelState::new().controllers;
+                // Setting an MSB clears its LSB, so every LSB comes after its MSB
+                for number in KEPT_ON_RESET {
+                    controllers.update(&ControllerMessage::from_raw(number, self.controllers.get(number)));
+                }
+                self.controllers = controllers;
+                self.pitch_wheel = CENTER as u32;
+                self.pressure = 0;
+                return self.parameters.update(message);
+            },
+            MidiEvent::ControlChange(message) => {
+                self.controllers.update(message);
+                return self.parameters.update(message);
+            },
+            MidiEvent::ProgramChange(program) => {
+                self.program = *program;
+                self.bank = (self.controllers.get(0x00), self.controllers.get(0x20));
+            },
+            MidiEvent::PitchWheelChange(value) => self.pitch_wheel = *value,
+            MidiEvent::ChannelPressure(pressure) => self.pressure = *pressure,
+            _ => {}
+        }
+        None
+    }
+
+    // Channel volume and expression include their LSB: 0-16383
+    pub fn volume(&self) -> u16 {
+        self.controllers.get_14bit(0x07)
+    }
+
+    pub fn pan(&self) -> u8 {
+        self.controllers.get(0x0A)
+    }
+
+    pub fn expression(&self) -> u16 {
+        self.controllers.get_14bit(0x0B)
+    }
+
+    pub fn damper_pedal(&self) -> bool {
+        self.controllers.is_on(0x40)
+    }
+
+    pub fn sostenuto_pedal(&self) -> bool {
+        self.controllers.is_on(0x42)
+    }
+
+    pub fn soft_pedal(&self) -> bool {
+        self.controllers.is_on(0x43)
+    }
+
+    // Detuning in semitones by the pitch wheel and the tuning parameters
+    pub fn pitch_offset(&self) -> f64 {
+        self.parameters.pitch_offset(self.pitch_wheel)
+    }
+}
+
+// States of all channels of all ports. Channels that never received an event are in their initial state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelStates {
+    channels: BTreeMap<ChannelId, ChannelState>,
+    initial: ChannelState,
+}
+
+impl ChannelStates {
+    pub fn new() -> Self {
+        Self { channels: BTreeMap::new(), initial: ChannelState::new() }
+    }
+
+    pub fn get(&self, channel: ChannelId) -> &ChannelState {
+        self.channels.get(&channel).unwrap_or(&self.initial)
+    }
+
+    // Channels that received events
+    pub fn iter(&self) -> impl Iterator<Item = (&ChannelId, &ChannelState)> {
+        self.channels.iter()
+    }
+
+    // The 16 channels of a port
+    pub fn port(&self, port: u8) -> impl Iterator<Item = (ChannelId, &ChannelState)> {
+        (0..16).map(move |channel| ChannelId::new(port, channel)).map(move |channel| (channel, self.get(channel)))
+    }
+
+    // Applies an event of a track on `port`. GM, GS and XG resets return all channels of the port to their initial state.
+    pub fn update(&mut self, port: u8, event: &Event) -> Option<ParameterChange> {
+        match event {
+            Event::Midi(channel, event) => {
+                let initial = &self.initial;
+                self.channels.entry(ChannelId::new(port, *channel)).or_insert_with(|| initial.clone()).update(event)
+            },
+            Event::Sysex(data) => {
+                match SysexMessage::parse(data) {
+                    SysexMessage::GmSystemOn | SysexMessage::GmSystemOff | SysexMessage::Gm2SystemOn | SysexMessage::GsReset | SysexMessage::XgSystemOn => {
+                        self.channels.retain(|channel, _| channel.port != port);
+                    },
+                    _ => {}
+                }
+                None
+            },
+            _ => None
+        }
+    }
+}
+
+// Channel states of a whole file. Snapshots taken at regular intervals make it possible to get the
+// states at any position by only replaying the events since the previous snapshot.
+#[derive(Debug, Clone)]
+pub struct ChannelTimeline {
+    events: Vec<(u64, u8, Event)>, // (absolute tick, port, event) of the channel and sysex events
+    snapshots: Vec<(usize, ChannelStates)>, // States before the event at the index
+    tempo_map: TempoMap,
+}
+
+impl ChannelTimeline {
+    pub fn new(file: &MidiFile) -> Self {
+        let mut ports = PortTracker::new(file);
+        let mut events = Vec::new();
+        for (tick, track, event) in file.merged_events() {
+            ports.update(track, event);
+            if matches!(event, Event::Midi(..) | Event::Sysex(_)) {
+                events.push((tick, ports.port(track), event.clone()));
+            }
+        }
+
+        let mut snapshots = Vec::with_capacity(events.len() / SNAPSHOT_INTERVAL + 1);
+        let mut states = ChannelStates::new();
+        for (index, (_, port, event)) in events.iter().enumerate() {
+            if index % SNAPSHOT_INTERVAL == 0 {
+                snapshots.push((index, states.clone()));
+            }
+            states.update(*port, event);
+        }
+        Self { events, snapshots, tempo_map: TempoMap::new(file) }
+    }
+
+    // States after all events up to and including `tick`
+    pub fn states_at(&self, tick: u64) -> ChannelStates {
+        let end = self.events.partition_point(|(event_tick, _, _)| *event_tick <= tick);
+        // The last snapshot that was taken before the end
+        let snapshot = self.snapshots.partition_point(|(index, _)| *index <= end).saturating_sub(1);
+        let (start, mut states) = match self.snapshots.get(snapshot) {
+            Some((index, states)) => (*index, states.clone()),
+            None => (0, ChannelStates::new()),
+        };
+        for (_, port, event) in &self.events[start..end] {
+            states.update(*port, event);
+        }
+        states
+    }
+
+    // States that playback starting at `seconds` begins with: the events before that moment are applied,
+    // the ones that happen exactly then are not
+    pub fn states_at_seconds(&self, seconds: f64) -> ChannelStates {
+        match self.tempo_map.first_tick_at(seconds) {
+            0 => ChannelStates::new(),
+            tick => self.states_at(tick - 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{HeaderChunk, TrackChunk, Format, Division, MetaEvent};
+
+    #[test]
+    fn states_at_matches_a_full_replay() {
+        // Several events share ticks, so that snapshots fall in between events of the same tick
+        let mut events = Vec::new();
+        for index in 0..1000u32 {
+            let dt = (index % 3 == 0) as u32;
+            let event = match index % 5 {
+                0 => Event::Midi((index % 4) as u8, MidiEvent::ControlChange(ControllerMessage::ChannelVolumeMSB((index % 128) as u8))),
+                1 => Event::Midi((index % 4) as u8, MidiEvent::ProgramChange((index % 128) as u8)),
+                2 => Event::Midi(1, MidiEvent::PitchWheelChange(index * 16)),
+                3 if index % 200 == 3 => Event::Sysex(vec![0x7E, 0x7F, 0x09, 0x01, 0xF7]),
+                _ => Event::Midi(2, MidiEvent::ChannelPressure((index % 128) as u8)),
+            };
+            events.push((dt, event));
+        }
+        events.push((0, Event::Meta(MetaEvent::EndOfTrack)));
+        let header = HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: Division::TicksPerQuarter(96) };
+        let file = MidiFile { header, tracks: vec![TrackChunk { events }], riff_info: None };
+        let timeline = ChannelTimeline::new(&file);
+
+        let replay = |end: u64| {
+            let mut states = ChannelStates::new();
+            for (_, _, event) in file.merged_events().filter(|(tick, _, _)| *tick <= end) {
+                states.update(0, event);
+            }
+            states
+        };
+        let boundaries = [SNAPSHOT_INTERVAL, 2 * SNAPSHOT_INTERVAL, 3 * SNAPSHOT_INTERVAL].map(|index| timeline.events[index].0);
+        let mut ticks = vec![0, 400, 1000];
+        for boundary in boundaries {
+            ticks.extend([boundary - 1, boundary, boundary + 1]);
+        }
+        for tick in ticks {
+            assert_eq!(timeline.states_at(tick), replay(tick), "tick {}", tick);
+        }
+    }
+
+    #[test]
+    fn states_at_seconds_follows_the_tempo() {
+        // 0.5 s for the first quarter note, 0.25 s for the following ones
+        let program = |dt, program| (dt, Event::Midi(0, MidiEvent::ProgramChange(program)));
+        let events = vec![
+            (0, Event::Meta(MetaEvent::SetTempo { tempo: 500_000 })),
+            program(96, 1),
+            (0, Event::Meta(MetaEvent::SetTempo { tempo: 250_000 })),
+            program(96, 2),
+            (0, Event::Meta(MetaEvent::EndOfTrack)),
+        ];
+        let header = HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: Division::TicksPerQuarter(96) };
+        let file = MidiFile { header, tracks: vec![TrackChunk { events }], riff_info: None };
+        let timeline = ChannelTimeline::new(&file);
+        let program_at = |seconds| timeline.states_at_seconds(seconds).get(ChannelId::new(0, 0)).program;
+        assert_eq!(program_at(0.0), 0);
+        assert_eq!(program_at(0.5), 0);
+        assert_eq!(program_at(0.501), 1);
+        assert_eq!(program_at(0.75), 1);
+        assert_eq!(program_at(0.751), 2);
+        assert_eq!(timeline.states_at_seconds(0.6), timeline.states_at(96));
+    }
+}
@@ -0,0 +1,255 @@
+
+use std::io;
+
+use crate::midi_parser::{MidiFile, MidiError, ParseOptions};
+use crate::midi_writer::WriteOptions;
+use crate::transform::{Transform, TrackSelector, is_valid_factor};
+use crate::quantize::{QuantizeOptions, HumanizeOptions};
+use crate::lint::{LintReport, Severity, json_string};
+use crate::text_encoding::TextEncoding;
+use crate::audio_generator::RenderOptions;
+use crate::notes::NoteOverlap;
+
+pub const TRANSFORM_USAGE: &str = "transformations:
+    --transpose SEMITONES     shift all notes except the drum channel
+    --tempo FACTOR            play faster (> 1) or slower (< 1) by scaling the tempo
+    --stretch FACTOR          scale the position of all events
+    --velocity FACTOR         scale note velocities
+    --compress THRESHOLD:RATIO  divide the part of velocities above THRESHOLD by RATIO
+    --remap FROM:TO           move the events of channel FROM (1-16) to channel TO
+    --drop-track INDEX|NAME   remove a track, can be repeated
+    --quantize GRID[:STRENGTH[:SWING]]  move note starts towards a grid of GRID notes per whole note
+    --quantize-ends GRID[:STRENGTH[:SWING]]  like --quantize, but also quantizes note ends
+    --humanize TICKS:VELOCITY[:SEED]  randomly shift notes by up to TICKS and change velocities";
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} expects a value", flag))?;
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+fn parse_factor(flag: &str, value: Option<&String>) -> Result<f64, String> {
+    let factor = parse_value(flag, value)?;
+    if is_valid_factor(factor) {
+        Ok(factor)
+    } else {
+        Err(format!("{} expects a positive number", flag))
+    }
+}
+
+fn parse_pair<A: std::str::FromStr, B: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<(A, B), String> {
+    let value: String = parse_value(flag, value)?;
+    let (a, b) = value.split_once(':').ok_or_else(|| format!("{} expects two values separated by ':'", flag))?;
+    Ok((parse_value(flag, Some(&a.to_string()))?, parse_value(flag, Some(&b.to_string()))?))
+}
+
+// GRID[:STRENGTH[:SWING]]
+fn parse_quantize(flag: &str, value: Option<&String>, quantize_ends: bool) -> Result<QuantizeOptions, String> {
+    let value: String = parse_value(flag, value)?;
+    let mut parts = value.split(':').map(|part| part.to_string());
+    let mut options = QuantizeOptions::new(parse_value(flag, parts.next().as_ref())?);
+    if let Some(strength) = parts.next() {
+        options.strength = parse_value(flag, Some(&strength))?;
+    }
+    if let Some(swing) = parts.next() {
+        options.swing = parse_value(flag, Some(&swing))?;
+    }
+    options.quantize_ends = quantize_ends;
+    Ok(options)
+}
+
+// TICKS:VELOCITY[:SEED]
+fn parse_humanize(flag: &str, value: Option<&String>) -> Result<HumanizeOptions, String> {
+    let value: String = parse_value(flag, value)?;
+    let parts = value.split(':').map(|part| part.to_string()).collect::<Vec<_>>();
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err(format!("{} expects TICKS:VELOCITY[:SEED]", flag));
+    }
+    Ok(HumanizeOptions {
+        timing: parse_value(flag, parts.first())?,
+        velocity: parse_value(flag, parts.get(1))?,
+        seed: if parts.len() == 3 { parse_value(flag, parts.get(2))? } else { 0 },
+    })
+}
+
+fn parse_channel(flag: &str, channel: u8) -> Result<u8, String> {
+    if (1..=16).contains(&channel) {
+        Ok(channel - 1)
+    } else {
+        Err(format!("{} expects channels from 1 to 16", flag))
+    }
+}
+
+// Removes `--encoding NAME` from the arguments. Texts that are not valid UTF-8 are decoded with it, the default is auto detection.
+pub fn take_encoding(args: &mut Vec<String>) -> Result<TextEncoding, String> {
+    let index = match args.iter().position(|arg| arg == "--encoding") {
+        Some(index) => index,
+        None => return Ok(TextEncoding::Auto)
+    };
+    let encoding = parse_value("--encoding", args.get(index + 1))?;
+    args.drain(index..index + 2);
+    Ok(encoding)
+}
+
+// Removes the options of rendering from the arguments: --lifo releases the note of a key that was
+// pressed last when it is held several times, --start SECONDS skips the beginning of the file
+pub fn take_render_options(args: &mut Vec<String>) -> Result<RenderOptions, String> {
+    let mut options = RenderOptions::new();
+    let count = args.len();
+    args.retain(|arg| arg != "--lifo");
+    if args.len() != count {
+        options.note_overlap = NoteOverlap::Lifo;
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--start") {
+        let seconds: f64 = parse_value("--start", args.get(index + 1))?;
+        if !seconds.is_finite() || seconds < 0.0 {
+            return Err(String::from("--start expects a number of seconds"));
+        }
+        options.start_seconds = seconds;
+        args.drain(index..index + 2);
+    }
+    Ok(options)
+}
+
+// Parses the transformation flags. All dropped tracks are collected into one transformation,
+// so that track indices refer to the input file.
+pub fn parse_transforms(args: &[String]) -> Result<Vec<Transform>, String> {
+    let mut transforms = Vec::new();
+    let mut dropped_tracks = Vec::new();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let transform = match flag.as_str() {
+            "--transpose" => Transform::Transpose(parse_value(flag, args.next())?),
+            "--tempo" => Transform::ScaleTempo(parse_factor(flag, args.next())?),
+            "--stretch" => Transform::TimeStretch(parse_factor(flag, args.next())?),
+            "--velocity" => Transform::ScaleVelocity(parse_factor(flag, args.next())?),
+            "--compress" => {
+                let (threshold, ratio) = parse_pair(flag, args.next())?;
+                if !is_valid_factor(ratio) {
+                    return Err(format!("{} expects a positive ratio", flag));
+                }
+                Transform::CompressVelocity { threshold, ratio }
+            },
+            "--remap" => {
+                let (from, to) = parse_pair(flag, args.next())?;
+                Transform::RemapChannel { from: parse_channel(flag, from)?, to: parse_channel(flag, to)? }
+            },
+            "--quantize" => Transform::Quantize(parse_quantize(flag, args.next(), false)?),
+            "--quantize-ends" => Transform::Quantize(parse_quantize(flag, args.next(), true)?),
+            "--humanize" => Transform::Humanize(parse_humanize(flag, args.next())?),
+            "--drop-track" => {
+                let track: String = parse_value(flag, args.next())?;
+                dropped_tracks.push(match track.parse() {
+                    Ok(index) => TrackSelector::Index(index),
+                    Err(_) => TrackSelector::Name(track),
+                });
+                continue;
+            },
+            _ => return Err(format!("Unknown option {}", flag))
+        };
+        transforms.push(transform);
+    }
+    if !dropped_tracks.is_empty() {
+        transforms.push(Transform::DropTracks(dropped_tracks));
+    }
+    Ok(transforms)
+}
+
+// Reads a midi file and reports the repairs that were necessary as warnings. "-" reads from stdin.
+pub fn read_input(path: &str, text_encoding: TextEncoding) -> Result<MidiFile, MidiError> {
+    let options = ParseOptions { text_encoding, ..ParseOptions::lenient() };
+    let (file, diagnostics) = if path == "-" {
+        MidiFile::from_reader_with_options(io::stdin().lock(), &options)?
+    } else {
+        MidiFile::read_midi_with_options(path, &options)?
+    };
+    for diagnostic in diagnostics {
+        eprintln!("WARNING: {}", diagnostic);
+    }
+    Ok(file)
+}
+
+// Writes a midi file. "-" writes to stdout.
+pub fn write_output(file: &MidiFile, path: &str) -> Result<(), MidiError> {
+    let options = WriteOptions::new();
+    if path == "-" {
+        file.to_writer(io::stdout().lock(), &options)
+    } else {
+        file.write_midi(path, &options)
+    }
+}
+
+// convert [0|1] [input] [output] [transformations]. Returns false if the arguments are invalid or the conversion failed.
+pub fn convert(program: &str, args: &[String], text_encoding: TextEncoding) -> bool {
+    let format = match args {
+        [format, _, _, ..] if format == "0" || format == "1" => format,
+        _ => {
+            eprintln!("usage: {} convert [0|1] [input] [output] [transformations]", program);
+            eprintln!("{}", TRANSFORM_USAGE);
+            return false;
+        }
+    };
+    let transforms = match parse_transforms(&args[3..]) {
+        Ok(transforms) => transforms,
+        Err(message) => {
+            eprintln!("{}", message);
+            return false;
+        }
+    };
+
+    let result = read_input(&args[1], text_encoding).and_then(|mut file| {
+        for transform in transforms.iter() {
+            file.apply(transform);
+        }
+        let converted = if format == "0" {
+            file.to_single_track()
+        } else {
+            file.to_multi_track()
+        };
+        write_output(&converted, &args[2])
+    });
+    if let Err(err) = &result {
+        eprintln!("{}", err);
+    }
+    result.is_ok()
+}
+
+// Checks the files and prints a report for each. Returns false if any file has errors.
+// With --strict the first structural problem makes a file unreadable instead of being repaired.
+pub fn lint(program: &str, args: &[String], text_encoding: TextEncoding) -> bool {
+    let json = args.iter().any(|arg| arg == "--json");
+    let strict = args.iter().any(|arg| arg == "--strict");
+    let paths = args.iter().filter(|arg| *arg != "--json" && *arg != "--strict").collect::<Vec<_>>();
+    if paths.is_empty() {
+        eprintln!("usage: {} lint [--json] [--strict] [input...]", program);
+        return false;
+    }
+
+    let options = ParseOptions { text_encoding, ..if strict { ParseOptions::strict() } else { ParseOptions::lenient() } };
+    let mut reports = Vec::new();
+    for path in paths {
+        let parsed = if path == "-" {
+            MidiFile::from_reader_with_options(io::stdin().lock(), &options)
+        } else {
+            MidiFile::read_midi_with_options(path, &options)
+        };
+        let report = match parsed {
+            Ok((file, diagnostics)) => {
+                let mut report = file.lint();
+                report.add_diagnostics(&diagnostics);
+                report
+            },
+            Err(err) => LintReport::unreadable(&err),
+        };
+        reports.push((path, report));
+    }
+
+    if json {
+        let files = reports.iter().map(|(path, report)| format!("{{\"path\":{},\"report\":{}}}", json_string(path), report.to_json())).collect::<Vec<_>>();
+        println!("[{}]", files.join(","));
+    } else {
+        for (path, report) in reports.iter() {
+            print!("{}: {}", path, report);
+        }
+    }
+    reports.iter().all(|(_, report)| report.count(Severity::Error) == 0)
+}
@@ -0,0 +1,192 @@
+
+use std::collections::BTreeMap;
+
+use crate::midi_parser::{MidiFile, HeaderChunk, TrackChunk, Format, Event, MetaEvent};
+use crate::ports::{PortMap, ChannelId};
+
+impl TrackChunk {
+    // Events with absolute ticks instead of delta times. `start` is added to every tick.
+    pub fn to_absolute(&self, start: u64) -> Vec<(u64, Event)> {
+        let mut tick = start;
+        self.events.iter().map(|(dt, event)| {
+            tick += *dt as u64;
+            (tick, event.clone())
+        }).collect()
+    }
+
+    // Builds a track from events with absolute ticks, which have to be sorted. Gaps that do not fit
+    // into a delta time saturate, the writer rejects them instead of writing wrong timing.
+    pub fn from_absolute(events: Vec<(u64, Event)>) -> Self {
+        let mut last_tick = 0;
+        let events = events.into_iter().map(|(tick, event)| {
+            let dt = tick.saturating_sub(last_tick);
+            last_tick = last_tick.max(tick);
+            (u32::try_from(dt).unwrap_or(u32::MAX), event)
+        }).collect();
+        Self { events }
+    }
+}
+
+fn end_of_track(events: &mut Vec<(u64, Event)>, end: u64) {
+    events.push((end, Event::Meta(MetaEvent::EndOfTrack)));
+}
+
+// Events that a MIDI Channel Prefix assigns to its channel. Timing, song wide and port metas stay in the conductor track.
+fn follows_prefix(event: &Event) -> bool {
+    match event {
+        Event::Sysex(_) | Event::Escape(_) => true,
+        Event::Meta(meta) => matches!(meta,
+            MetaEvent::MidiChannelPrefix { .. } | MetaEvent::Text { .. } | MetaEvent::SequenceTrackName { .. } | MetaEvent::InstrumentName { .. } |
+            MetaEvent::Lyric { .. } | MetaEvent::ProgramName { .. } | MetaEvent::SequencerSpecific { .. } | MetaEvent::Unknown { .. }
+        ),
+        Event::Midi(..) => false,
+    }
+}
+
+// An event with its absolute tick, the port of its track and the channel of the MIDI Channel Prefix it follows
+struct AbsoluteEvent {
+    tick: u64,
+    port: u8,
+    prefix: Option<u8>,
+    event: Event,
+}
+
+impl MidiFile {
+    // All events of all tracks. Events on the same tick keep the order of their tracks, the End of Track
+    // events are dropped. Also returns the tick at which the last track ends.
+    fn absolute_events(&self, port_map: &PortMap) -> (Vec<AbsoluteEvent>, u64) {
+        let mut events = Vec::new();
+        let mut end = 0;
+        for (track, start) in self.tracks.iter().zip(self.get_track_start_ticks()) {
+            let mut port = 0;
+            let mut prefix = None;
+            for (tick, event) in track.to_absolute(start) {
+                end = end.max(tick);
+                port = port_map.next_port(port, &event);
+                // A prefix lasts until the next channel event or the next prefix
+                match event {
+                    Event::Meta(MetaEvent::MidiChannelPrefix { channel }) => prefix = Some(channel & 0xF),
+                    Event::Midi(..) => prefix = None,
+                    _ => {}
+                }
+                if event != Event::Meta(MetaEvent::EndOfTrack) {
+                    events.push(AbsoluteEvent { tick, port, prefix: prefix.filter(|_| follows_prefix(&event)), event });
+                }
+            }
+        }
+        // Stable sort: the order inside each track is kept
+        events.sort_by_key(|event| event.tick);
+        (events, end)
+    }
+
+    // Merges all tracks into the single track of a format 0 file. MIDI Port events are inserted
+    // wherever the port changes, so that channels of different ports stay apart.
+    pub fn to_single_track(&self) -> MidiFile {
+        let port_map = PortMap::new(self);
+        let (events, end) = self.absolute_events(&port_map);
+        let mut merged = Vec::with_capacity(events.len());
+        let mut current_port = 0;
+        for AbsoluteEvent { tick, port, event, .. } in events {
+            match event {
+                Event::Meta(MetaEvent::MidiPort { .. }) => continue,
+                Event::Midi(..) if port != current_port => {
+                    merged.push((tick, Event::Meta(MetaEvent::MidiPort { port })));
+                    current_port = port;
+                },
+                _ => {}
+            }
+            // Device names switch the port of the merged track as well
+            current_port = port_map.next_port(current_port, &event);
+            merged.push((tick, event));
+        }
+        end_of_track(&mut merged, end);
+
+        MidiFile {
+            header: HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: self.header.division },
+            tracks: vec![TrackChunk::from_absolute(merged)],
+            riff_info: self.riff_info.clone(),
+        }
+    }
+
+    // Splits the file into a format 1 file. The first track holds the meta and sysex events,
+    // every used channel gets a track of its own that starts with its port if that is not the first one.
+    // MIDI Channel Prefixes move along with the events they apply to into the track of their channel.
+    pub fn to_multi_track(&self) -> MidiFile {
+        let (events, end) = self.absolute_events(&PortMap::new(self));
+        let mut conductor = Vec::new();
+        let mut channels: BTreeMap<ChannelId, Vec<(u64, Event)>> = BTreeMap::new();
+        for AbsoluteEvent { tick, port, prefix, event } in events {
+            let channel = match (&event, prefix) {
+                (Event::Meta(MetaEvent::MidiPort { .. }), _) => continue,
+                (Event::Midi(channel, _), _) => ChannelId::new(port, *channel),
+                (_, Some(channel)) => ChannelId::new(port, channel),
+                _ => {
+                    conductor.push((tick, event));
+                    continue;
+                }
+            };
+            channels.entry(channel).or_insert_with(|| match channel.port {
+                0 => Vec::new(),
+                port => vec![(0, Event::Meta(MetaEvent::MidiPort { port }))],
+            }).push((tick, event));
+        }
+
+        let mut tracks = vec![conductor];
+        tracks.extend(channels.into_values());
+        let tracks = tracks.into_iter().map(|mut events| {
+            end_of_track(&mut events, end);
+            TrackChunk::from_absolute(events)
+        }).collect::<Vec<_>>();
+
+        MidiFile {
+            header: HeaderChunk { format: Format::SimulTrack, ntrks: tracks.len() as u32, division: self.header.division },
+            tracks,
+            riff_info: self.riff_info.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{Division, MidiEvent};
+
+    #[test]
+    fn from_absolute_saturates_long_gaps() {
+        let end = Event::Meta(MetaEvent::EndOfTrack);
+        let track = TrackChunk::from_absolute(vec![(10, end.clone()), (u32::MAX as u64 + 10, end.clone()), (u32::MAX as u64 * 3, end.clone())]);
+        assert_eq!(track.events.iter().map(|(dt, _)| *dt).collect::<Vec<_>>(), [10, u32::MAX, u32::MAX]);
+    }
+
+    #[test]
+    fn channel_prefix_moves_to_the_channel_track() {
+        let meta = |meta| (0, Event::Meta(meta));
+        let events = vec![
+            meta(MetaEvent::SetTempo { tempo: 400_000 }),
+            meta(MetaEvent::MidiChannelPrefix { channel: 1 }),
+            meta(MetaEvent::InstrumentName { text: "Bass".into() }),
+            meta(MetaEvent::SetTempo { tempo: 300_000 }),
+            (0, Event::Midi(0, MidiEvent::ProgramChange(1))),
+            meta(MetaEvent::InstrumentName { text: "Unscoped".into() }),
+            (0, Event::Midi(1, MidiEvent::ProgramChange(33))),
+            meta(MetaEvent::EndOfTrack),
+        ];
+        let header = HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: Division::TicksPerQuarter(96) };
+        let file = MidiFile { header, tracks: vec![TrackChunk { events }], riff_info: None };
+        let split = file.to_multi_track();
+        let events = |track: usize| split.tracks[track].events.iter().map(|(_, event)| event.clone()).collect::<Vec<_>>();
+        assert_eq!(events(0), [
+            Event::Meta(MetaEvent::SetTempo { tempo: 400_000 }),
+            Event::Meta(MetaEvent::SetTempo { tempo: 300_000 }),
+            Event::Meta(MetaEvent::InstrumentName { text: "Unscoped".into() }),
+            Event::Meta(MetaEvent::EndOfTrack),
+        ]);
+        assert_eq!(events(1), [Event::Midi(0, MidiEvent::ProgramChange(1)), Event::Meta(MetaEvent::EndOfTrack)]);
+        assert_eq!(events(2), [
+            Event::Meta(MetaEvent::MidiChannelPrefix { channel: 1 }),
+            Event::Meta(MetaEvent::InstrumentName { text: "Bass".into() }),
+            Event::Midi(1, MidiEvent::ProgramChange(33)),
+            Event::Meta(MetaEvent::EndOfTrack),
+        ]);
+    }
+}
@@ -0,0 +1,210 @@
+
+// Built-in instrument names of the General MIDI, GM2, Roland GS and Yamaha XG sound sets
+
+pub const GM1_NAMES: [&str; 128] = [
+    "Acoustic Grand Piano", "Bright Acoustic Piano", "Electric Grand Piano", "Honky-tonk Piano",
+    "Electric Piano 1", "Electric Piano 2", "Harpsichord", "Clavinet",
+    "Celesta", "Glockenspiel", "Music Box", "Vibraphone",
+    "Marimba", "Xylophone", "Tubular Bells", "Dulcimer",
+    "Drawbar Organ", "Percussive Organ", "Rock Organ", "Church Organ",
+    "Reed Organ", "Accordion", "Harmonica", "Tango Accordion",
+    "Acoustic Guitar (nylon)", "Acoustic Guitar (steel)", "Electric Guitar (jazz)", "Electric Guitar (clean)",
+    "Electric Guitar (muted)", "Overdriven Guitar", "Distortion Guitar", "Guitar Harmonics",
+    "Acoustic Bass", "Electric Bass (finger)", "Electric Bass (pick)", "Fretless Bass",
+    "Slap Bass 1", "Slap Bass 2", "Synth Bass 1", "Synth Bass 2",
+    "Violin", "Viola", "Cello", "Contrabass",
+    "Tremolo Strings", "Pizzicato Strings", "Orchestral Harp", "Timpani",
+    "String Ensemble 1", "String Ensemble 2", "Synth Strings 1", "Synth Strings 2",
+    "Choir Aahs", "Voice Oohs", "Synth Voice", "Orchestra Hit",
+    "Trumpet", "Trombone", "Tuba", "Muted Trumpet",
+    "French Horn", "Brass Section", "Synth Brass 1", "Synth Brass 2",
+    "Soprano Sax", "Alto Sax", "Tenor Sax", "Baritone Sax",
+    "Oboe", "English Horn", "Bassoon", "Clarinet",
+    "Piccolo", "Flute", "Recorder", "Pan Flute",
+    "Blown Bottle", "Shakuhachi", "Whistle", "Ocarina",
+    "Lead 1 (square)", "Lead 2 (sawtooth)", "Lead 3 (calliope)", "Lead 4 (chiff)",
+    "Lead 5 (charang)", "Lead 6 (voice)", "Lead 7 (fifths)", "Lead 8 (bass + lead)",
+    "Pad 1 (new age)", "Pad 2 (warm)", "Pad 3 (polysynth)", "Pad 4 (choir)",
+    "Pad 5 (bowed)", "Pad 6 (metallic)", "Pad 7 (halo)", "Pad 8 (sweep)",
+    "FX 1 (rain)", "FX 2 (soundtrack)", "FX 3 (crystal)", "FX 4 (atmosphere)",
+    "FX 5 (brightness)", "FX 6 (goblins)", "FX 7 (echoes)", "FX 8 (sci-fi)",
+    "Sitar", "Banjo", "Shamisen", "Koto",
+    "Kalimba", "Bagpipe", "Fiddle", "Shanai",
+    "Tinkle Bell", "Agogo", "Steel Drums", "Woodblock",
+    "Taiko Drum", "Melodic Tom", "Synth Drum", "Reverse Cymbal",
+    "Guitar Fret Noise", "Breath Noise", "Seashore", "Bird Tweet",
+    "Telephone Ring", "Helicopter", "Applause", "Gunshot",
+];
+
+// (program, bank LSB, name) of the GM2 variations. Bank MSB 121 selects the melodic sounds.
+pub const GM2_VARIATIONS: &[(u8, u8, &str)] = &[
+    (0, 1, "Wide Acoustic Grand"), (0, 2, "Dark Acoustic Grand"),
+    (1, 1, "Wide Bright Acoustic"),
+    (2, 1, "Wide Electric Grand"),
+    (3, 1, "Wide Honky-tonk"),
+    (4, 1, "Detuned Electric Piano 1"), (4, 2, "Electric Piano 1 Variation"), (4, 3, "60's Electric Piano"),
+    (5, 1, "Detuned Electric Piano 2"), (5, 2, "Electric Piano 2 Variation"), (5, 3, "Electric Piano Legend"), (5, 4, "Electric Piano Phase"),
+    (6, 1, "Coupled Harpsichord"), (6, 2, "Wide Harpsichord"), (6, 3, "Open Harpsichord"),
+    (7, 1, "Pulse Clavinet"),
+    (11, 1, "Wet Vibraphone"),
+    (12, 1, "Wide Marimba"),
+    (14, 1, "Church Bells"), (14, 2, "Carillon"),
+    (16, 1, "Detuned Drawbar Organ"), (16, 2, "Italian 60's Organ"), (16, 3, "Drawbar Organ 2"),
+    (17, 1, "Detuned Percussive Organ"), (17, 2, "Percussive Organ 2"),
+    (19, 1, "Church Organ (octave mix)"), (19, 2, "Detuned Church Organ"),
+    (20, 1, "Puff Organ"),
+    (21, 1, "Italian Accordion"),
+    (24, 1, "Ukulele"), (24, 2, "Open Nylon Guitar"), (24, 3, "Nylon Guitar 2"),
+    (25, 1, "12-Strings Guitar"), (25, 2, "Mandolin"), (25, 3, "Steel + Body"),
+    (26, 1, "Pedal Steel Guitar"),
+    (27, 1, "Detuned Clean Electric Guitar"), (27, 2, "Mid Tone Guitar"),
+    (28, 1, "Funk Guitar"), (28, 2, "Funk Guitar 2"), (28, 3, "Jazz Man"),
+    (29, 1, "Guitar Pinch"),
+    (30, 1, "Feedback Guitar"), (30, 2, "Distortion Rhythm Guitar"),
+    (31, 1, "Guitar Feedback"),
+    (33, 1, "Finger Slap Bass"),
+    (38, 1, "Synth Bass 101"), (38, 2, "Synth Bass 3 (resonance)"), (38, 3, "Clavi Bass"), (38, 4, "Hammer"),
+    (39, 1, "Synth Bass 4 (attack)"), (39, 2, "Synth Bass (rubber)"), (39, 3, "Attack Pulse"),
+    (40, 1, "Slow Violin"),
+    (46, 1, "Yang Qin"),
+    (48, 1, "Strings and Brass"), (48, 2, "60's Strings"),
+    (50, 1, "Synth Strings 3"),
+    (52, 1, "Choir Aahs 2"),
+    (53, 1, "Humming"),
+    (54, 1, "Analog Voice"),
+    (55, 1, "Bass Hit Plus"), (55, 2, "6th Hit"), (55, 3, "Euro Hit"),
+    (56, 1, "Dark Trumpet Soft"),
+    (57, 1, "Trombone 2"), (57, 2, "Bright Trombone"),
+    (59, 1, "Muted Trumpet 2"),
+    (60, 1, "French Horn 2 (warm)"),
+    (61, 1, "Brass Section 2 (octave mix)"),
+    (62, 1, "Synth Brass 3"), (62, 2, "Analog Synth Brass 1"), (62, 3, "Jump Brass"),
+    (63, 1, "Synth Brass 4"), (63, 2, "Analog Synth Brass 2"),
+    (80, 1, "Square Lead 2"), (80, 2, "Sine Wave"),
+    (81, 1, "Saw Lead 2"), (81, 2, "Doctor Solo"), (81, 3, "Natural Lead"), (81, 4, "Sequenced Saw"),
+    (89, 1, "Sine Pad"),
+    (91, 1, "Itopia"),
+    (98, 1, "Synth Mallet"),
+    (102, 1, "Echo Bell"), (102, 2, "Echo Pan"),
+    (104, 1, "Sitar 2 (bend)"),
+    (107, 1, "Taisho Koto"),
+    (115, 1, "Castanets"),
+    (116, 1, "Concert Bass Drum"),
+    (117, 1, "Melodic Tom 2 (power)"),
+    (118, 1, "Rhythm Box Tom"), (118, 2, "Electric Drum"),
+    (120, 1, "Guitar Cutting Noise"), (120, 2, "Acoustic Bass String Slap"),
+    (121, 1, "Flute Key Click"),
+    (122, 1, "Rain"), (122, 2, "Thunder"), (122, 3, "Wind"), (122, 4, "Stream"), (122, 5, "Bubble"),
+    (123, 1, "Dog"), (123, 2, "Horse Gallop"), (123, 3, "Bird Tweet 2"),
+    (124, 1, "Telephone Ring 2"), (124, 2, "Door Creaking"), (124, 3, "Door"), (124, 4, "Scratch"), (124, 5, "Wind Chime"),
+    (125, 1, "Car Engine"), (125, 2, "Car Stop"), (125, 3, "Car Pass"), (125, 4, "Car Crash"), (125, 5, "Siren"),
+    (125, 6, "Train"), (125, 7, "Jetplane"), (125, 8, "Starship"), (125, 9, "Burst Noise"),
+    (126, 1, "Laughing"), (126, 2, "Screaming"), (126, 3, "Punch"), (126, 4, "Heart Beat"), (126, 5, "Footsteps"),
+    (127, 1, "Machine Gun"), (127, 2, "Lasergun"), (127, 3, "Explosion"),
+];
+
+// Capital tones of the Roland SC-55, selected with bank MSB 0
+pub const GS_CAPITAL_NAMES: [&str; 128] = [
+    "Piano 1", "Piano 2", "Piano 3", "Honky-tonk", "E.Piano 1", "E.Piano 2", "Harpsichord", "Clav.",
+    "Celesta", "Glockenspiel", "Music Box", "Vibraphone", "Marimba", "Xylophone", "Tubular-bell", "Santur",
+    "Organ 1", "Organ 2", "Organ 3", "Church Org.1", "Reed Organ", "Accordion Fr", "Harmonica", "Bandneon",
+    "Nylon-str.Gt", "Steel-str.Gt", "Jazz Gt.", "Clean Gt.", "Muted Gt.", "Overdrive Gt", "DistortionGt", "Gt.Harmonics",
+    "Acoustic Bs.", "Fingered Bs.", "Picked Bs.", "Fretless Bs.", "Slap Bass 1", "Slap Bass 2", "Synth Bass 1", "Synth Bass 2",
+    "Violin", "Viola", "Cello", "Contrabass", "Tremolo Str", "PizzicatoStr", "Harp", "Timpani",
+    "Strings", "Slow Strings", "Syn.Strings1", "Syn.Strings2", "Choir Aahs", "Voice Oohs", "SynVox", "OrchestraHit",
+    "Trumpet", "Trombone", "Tuba", "MutedTrumpet", "French Horn", "Brass 1", "Synth Brass1", "Synth Brass2",
+    "Soprano Sax", "Alto Sax", "Tenor Sax", "Baritone Sax", "Oboe", "English Horn", "Bassoon", "Clarinet",
+    "Piccolo", "Flute", "Recorder", "Pan Flute", "Bottle Blow", "Shakuhachi", "Whistle", "Ocarina",
+    "Square Wave", "Saw Wave", "Syn.Calliope", "Chiffer Lead", "Charang", "Solo Vox", "5th Saw Wave", "Bass & Lead",
+    "Fantasia", "Warm Pad", "Polysynth", "Space Voice", "Bowed Glass", "Metal Pad", "Halo Pad", "Sweep Pad",
+    "Ice Rain", "Soundtrack", "Crystal", "Atmosphere", "Brightness", "Goblin", "Echo Drops", "Star Theme",
+    "Sitar", "Banjo", "Shamisen", "Koto", "Kalimba", "Bag Pipe", "Fiddle", "Shanai",
+    "Tinkle Bell", "Agogo", "Steel Drums", "Woodblock", "Taiko", "Melo. Tom 1", "Synth Drum", "Reverse Cym.",
+    "Gt.FretNoise", "Breath Noise", "Seashore", "Bird", "Telephone 1", "Helicopter", "Applause", "Gun Shot",
+];
+
+// (program, bank MSB, name) of the GS variation tones
+pub const GS_VARIATIONS: &[(u8, u8, &str)] = &[
+    (0, 8, "Piano 1w"), (0, 16, "Piano 1d"),
+    (1, 8, "Piano 2w"),
+    (2, 8, "Piano 3w"),
+    (3, 8, "Honky-tonk w"),
+    (4, 8, "Detuned EP 1"), (4, 16, "E.Piano 1w"), (4, 24, "60's E.Piano"),
+    (5, 8, "Detuned EP 2"), (5, 16, "E.Piano 2w"),
+    (6, 8, "Coupled Hps."), (6, 16, "Harpsi.w"), (6, 24, "Harpsi.o"),
+    (11, 8, "Vib.w"),
+    (12, 8, "Marimba w"),
+    (14, 8, "Church Bell"), (14, 9, "Carillon"),
+    (16, 8, "Detuned Or.1"), (16, 16, "60's Organ 1"), (16, 32, "Organ 4"),
+    (17, 8, "Detuned Or.2"), (17, 32, "Organ 5"),
+    (19, 8, "Church Org.2"), (19, 16, "Church Org.3"),
+    (20, 32, "Puff Organ"),
+    (21, 8, "Accordion It"),
+    (24, 8, "Ukulele"), (24, 16, "Nylon Gt.o"), (24, 32, "Nylon Gt.2"),
+    (25, 8, "12-str.Gt"), (25, 16, "Mandolin"),
+    (26, 8, "Hawaiian Gt."),
+    (27, 8, "Chorus Gt."),
+    (28, 8, "Funk Gt."), (28, 16, "Funk Gt.2"),
+    (30, 8, "Feedback Gt."),
+    (31, 8, "Gt. Feedback"),
+    (38, 1, "SynthBass101"), (38, 8, "Synth Bass 3"),
+    (39, 8, "Synth Bass 4"), (39, 16, "Rubber Bass"),
+    (40, 8, "Slow Violin"),
+    (48, 8, "Orchestra"),
+    (50, 8, "Syn.Strings3"),
+    (52, 32, "Choir Aahs 2"),
+    (57, 1, "Trombone 2"),
+    (60, 1, "Fr.Horn 2"),
+    (61, 8, "Brass 2"),
+    (62, 8, "Synth Brass3"), (62, 16, "AnalogBrass1"),
+    (63, 8, "Synth Brass4"), (63, 16, "AnalogBrass2"),
+    (80, 1, "Square"), (80, 8, "Sine Wave"),
+    (81, 1, "Saw"), (81, 8, "Doctor Solo"),
+    (98, 1, "Syn Mallet"),
+    (102, 1, "Echo Bell"), (102, 2, "Echo Pan"),
+    (104, 1, "Sitar 2"),
+    (107, 8, "Taisho Koto"),
+    (115, 8, "Castanets"),
+    (116, 8, "Concert BD"),
+    (117, 8, "Melo. Tom 2"),
+    (118, 8, "808 Tom"), (118, 9, "Elec Perc."),
+    (120, 1, "Gt.Cut Noise"), (120, 2, "String Slap"),
+    (121, 1, "Fl.Key Click"),
+    (122, 1, "Rain"), (122, 2, "Thunder"), (122, 3, "Wind"), (122, 4, "Stream"), (122, 5, "Bubble"),
+    (123, 1, "Dog"), (123, 2, "Horse-Gallop"), (123, 3, "Bird 2"),
+    (124, 1, "Telephone 2"), (124, 2, "DoorCreaking"), (124, 3, "Door"), (124, 4, "Scratch"), (124, 5, "Wind Chimes"),
+    (125, 1, "Car-Engine"), (125, 2, "Car-Stop"), (125, 3, "Car-Pass"), (125, 4, "Car-Crash"), (125, 5, "Siren"),
+    (125, 6, "Train"), (125, 7, "Jetplane"), (125, 8, "Starship"), (125, 9, "Burst Noise"),
+    (126, 1, "Laughing"), (126, 2, "Screaming"), (126, 3, "Punch"), (126, 4, "Heart Beat"), (126, 5, "Footsteps"),
+    (127, 1, "Machine Gun"), (127, 2, "Lasergun"), (127, 3, "Explosion"),
+];
+
+// (program, name) of the drum kits of GM2 and GS. GM1 only knows the standard kit.
+pub const DRUM_KITS: &[(u8, &str)] = &[
+    (0, "Standard Kit"), (8, "Room Kit"), (16, "Power Kit"), (24, "Electronic Kit"), (25, "TR-808 Kit"),
+    (32, "Jazz Kit"), (40, "Brush Kit"), (48, "Orchestra Kit"), (56, "SFX Kit"), (127, "CM-64/32L Kit"),
+];
+
+// (program, name) of the XG drum kits on bank MSB 127
+pub const XG_DRUM_KITS: &[(u8, &str)] = &[
+    (0, "Standard Kit"), (1, "Standard Kit 2"), (8, "Room Kit"), (16, "Rock Kit"), (24, "Electro Kit"),
+    (25, "Analog Kit"), (32, "Jazz Kit"), (40, "Brush Kit"), (48, "Classic Kit"),
+];
+
+// (program, name) of the XG sound effect kits on bank MSB 126
+pub const XG_SFX_KITS: &[(u8, &str)] = &[
+    (0, "SFX Kit 1"), (1, "SFX Kit 2"),
+];
+
+// (program, name) of the XG sound effect voices on bank MSB 64
+pub const XG_SFX_VOICES: &[(u8, &str)] = &[
+    (0, "Cutting Noise"), (1, "Cutting Noise 2"), (3, "String Slap"), (16, "Flute Key Click"),
+    (32, "Shower"), (33, "Thunder"), (34, "Wind"), (35, "Stream"), (36, "Bubble"), (37, "Feed"),
+    (48, "Dog"), (49, "Horse Gallop"), (50, "Bird 2"), (54, "Ghost"), (55, "Maou"),
+    (64, "Telephone Dial"), (65, "Door Squeak"), (66, "Door Slam"), (67, "Scratch Cut"), (68, "Scratch Split"),
+    (69, "Wind Chime"), (70, "Telephone Ring 2"),
+    (80, "Car Engine Ignition"), (81, "Car Tires Squeal"), (82, "Car Passing"), (83, "Car Crash"), (84, "Siren"),
+    (85, "Train"), (86, "Jet Plane"), (87, "Starship"), (88, "Burst"), (89, "Roller Coaster"), (90, "Submarine"),
+    (96, "Laugh"), (97, "Scream"), (98, "Punch"), (99, "Heartbeat"), (100, "Foot Steps"),
+    (112, "Machine Gun"), (113, "Laser Gun"), (114, "Explosion"), (115, "Firework"),
+];
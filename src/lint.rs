@@ -0,0 +1,436 @@
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Display;
+
+use crate::midi_parser::{MidiFile, MidiError, Format, Event, MidiEvent, MetaEvent, Diagnostic, DiagnosticKind};
+use crate::notes::NoteOverlap;
+use crate::ports::{PortTracker, ChannelId};
+
+const MAX_FINDINGS_PER_KIND: usize = 100; // Further findings of a kind are only counted
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        }
+    }
+}
+
+// The structural problems come from the repairs of the parser, the others from checking the parsed file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintKind {
+    UnreadableFile,
+    JunkBeforeHeader,
+    UnknownChunk,
+    WrongChunkLength,
+    InvalidEvent,
+    JunkBetweenChunks,
+    TrailingData,
+    EventAfterEndOfTrack,
+    MissingEndOfTrack,
+    UnterminatedSysex,
+    TrackCountMismatch, // The header announces another number of tracks than the file contains
+    UnterminatedNote, // Note on without a matching note off
+    OverlappingNote, // Note on for a key that is still held on the same channel
+    DataOutOfRange,
+    TempoOutsideConductor, // Tempo events of format 1 files belong into the first track
+    UnusedChannel, // Channel events on a channel that never plays a note
+}
+
+impl From<DiagnosticKind> for LintKind {
+    fn from(kind: DiagnosticKind) -> Self {
+        match kind {
+            DiagnosticKind::JunkBeforeHeader => LintKind::JunkBeforeHeader,
+            DiagnosticKind::UnknownChunk => LintKind::UnknownChunk,
+            DiagnosticKind::WrongChunkLength => LintKind::WrongChunkLength,
+            DiagnosticKind::InvalidEvent => LintKind::InvalidEvent,
+            DiagnosticKind::JunkBetweenChunks => LintKind::JunkBetweenChunks,
+            DiagnosticKind::TrailingData => LintKind::TrailingData,
+            DiagnosticKind::MissingEndOfTrack => LintKind::MissingEndOfTrack,
+            DiagnosticKind::DataAfterEndOfTrack => LintKind::EventAfterEndOfTrack,
+            DiagnosticKind::UnterminatedSysex => LintKind::UnterminatedSysex,
+            DiagnosticKind::TrackCountMismatch => LintKind::TrackCountMismatch,
+        }
+    }
+}
+
+impl LintKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LintKind::UnreadableFile => "unreadable-file",
+            LintKind::JunkBeforeHeader => "junk-before-header",
+            LintKind::UnknownChunk => "unknown-chunk",
+            LintKind::WrongChunkLength => "wrong-chunk-length",
+            LintKind::InvalidEvent => "invalid-event",
+            LintKind::JunkBetweenChunks => "junk-between-chunks",
+            LintKind::TrailingData => "trailing-data",
+            LintKind::EventAfterEndOfTrack => "event-after-end-of-track",
+            LintKind::MissingEndOfTrack => "missing-end-of-track",
+            LintKind::UnterminatedSysex => "unterminated-sysex",
+            LintKind::TrackCountMismatch => "track-count-mismatch",
+            LintKind::UnterminatedNote => "unterminated-note",
+            LintKind::OverlappingNote => "overlapping-note",
+            LintKind::DataOutOfRange => "data-out-of-range",
+            LintKind::TempoOutsideConductor => "tempo-outside-conductor",
+            LintKind::UnusedChannel => "unused-channel",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            LintKind::UnreadableFile | LintKind::InvalidEvent | LintKind::EventAfterEndOfTrack | LintKind::MissingEndOfTrack => Severity::Error,
+            LintKind::TrackCountMismatch | LintKind::DataOutOfRange => Severity::Error,
+            LintKind::UnusedChannel => Severity::Info,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub kind: LintKind,
+    pub message: String,
+    pub track: Option<usize>,
+    pub tick: Option<u64>, // Absolute ticks
+    pub channel: Option<ChannelId>,
+    pub offset: Option<u64>, // Byte position in the file
+}
+
+impl Finding {
+    fn new(kind: LintKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into(), track: None, tick: None, channel: None, offset: None }
+    }
+
+    fn in_track(mut self, track: usize) -> Self {
+        self.track = Some(track);
+        self
+    }
+
+    fn at_tick(mut self, tick: u64) -> Self {
+        self.tick = Some(tick);
+        self
+    }
+
+    fn on_channel(mut self, channel: ChannelId) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<7}", self.kind.severity().name())?;
+        let mut location = Vec::new();
+        if let Some(track) = self.track {
+            location.push(format!("track {}", track));
+        }
+        if let Some(tick) = self.tick {
+            location.push(format!("tick {}", tick));
+        }
+        if let Some(channel) = self.channel {
+            location.push(format!("channel {}", channel));
+        }
+        if let Some(offset) = self.offset {
+            location.push(format!("byte {}", offset));
+        }
+        if !location.is_empty() {
+            write!(f, " {}:", location.join(", "))?;
+        }
+        write!(f, " {} [{}]", self.message, self.kind.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LintReport {
+    pub findings: Vec<Finding>,
+    counts: Vec<(LintKind, usize)>, // Number of findings per kind, including the ones that were not kept
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_option<T: Display>(value: Option<T>) -> String {
+    value.map_or(String::from("null"), |value| value.to_string())
+}
+
+impl LintReport {
+    fn push(&mut self, finding: Finding) {
+        let count = match self.counts.iter_mut().find(|(kind, _)| *kind == finding.kind) {
+            Some((_, count)) => count,
+            None => {
+                self.counts.push((finding.kind, 0));
+                &mut self.counts.last_mut().unwrap().1
+            }
+        };
+        *count += 1;
+        if *count <= MAX_FINDINGS_PER_KIND {
+            self.findings.push(finding);
+        }
+    }
+
+    // Adds the problems that the lenient parser repaired
+    pub fn add_diagnostics(&mut self, diagnostics: &[Diagnostic]) {
+        for diagnostic in diagnostics {
+            let mut finding = Finding::new(diagnostic.kind.into(), diagnostic.message.clone());
+            finding.track = diagnostic.track;
+            finding.offset = diagnostic.offset;
+            self.push(finding);
+        }
+    }
+
+    pub fn unreadable(error: &MidiError) -> Self {
+        let mut report = Self::default();
+        report.push(Finding::new(LintKind::UnreadableFile, error.to_string()));
+        report
+    }
+
+    // Number of findings with the severity, including the ones that were not kept
+    pub fn count(&self, severity: Severity) -> usize {
+        self.counts.iter().filter(|(kind, _)| kind.severity() == severity).map(|(_, count)| count).sum()
+    }
+
+    fn omitted(&self) -> usize {
+        self.counts.iter().map(|(_, count)| count.saturating_sub(MAX_FINDINGS_PER_KIND)).sum()
+    }
+
+    pub fn to_json(&self) -> String {
+        let findings = self.findings.iter().map(|finding| format!(
+            "{{\"kind\":{},\"severity\":{},\"message\":{},\"track\":{},\"tick\":{},\"channel\":{},\"offset\":{}}}",
+            json_string(finding.kind.name()), json_string(finding.kind.severity().name()), json_string(&finding.message),
+            json_option(finding.track), json_option(finding.tick), json_option(finding.channel.map(|channel| json_string(&channel.to_string()))),
+            json_option(finding.offset)
+        )).collect::<Vec<_>>();
+        format!("{{\"errors\":{},\"warnings\":{},\"infos\":{},\"omitted\":{},\"findings\":[{}]}}",
+            self.count(Severity::Error), self.count(Severity::Warning), self.count(Severity::Info), self.omitted(), findings.join(","))
+    }
+}
+
+impl Display for LintReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} errors, {} warnings, {} infos", self.count(Severity::Error), self.count(Severity::Warning), self.count(Severity::Info))?;
+        let mut findings = self.findings.iter().collect::<Vec<_>>();
+        findings.sort_by_key(|finding| finding.kind.severity());
+        for finding in findings {
+            writeln!(f, "  {}", finding)?;
+        }
+        if self.omitted() > 0 {
+            writeln!(f, "  ... {} more findings not shown", self.omitted())?;
+        }
+        Ok(())
+    }
+}
+
+// Describes the data bytes of an event that do not fit into their range
+fn out_of_range(event: &Event) -> Option<String> {
+    let byte = |name: &str, value: u8| if value > 0x7F { Some(format!("{} {} is above 127", name, value)) } else { None };
+    match event {
+        Event::Midi(channel, _) if *channel > 0xF => Some(format!("Channel {} is above 15", channel)),
+        Event::Midi(_, MidiEvent::NoteOn { key, velocity }) | Event::Midi(_, MidiEvent::NoteOff { key, velocity }) => {
+            byte("Key", *key).or_else(|| byte("Velocity", *velocity))
+        },
+        Event::Midi(_, MidiEvent::PolyphonicKeyPressure { key, pressure }) => byte("Key", *key).or_else(|| byte("Pressure", *pressure)),
+        Event::Midi(_, MidiEvent::ControlChange(message)) => {
+            let (number, value) = message.to_raw();
+            byte("Controller number", number).or_else(|| byte(&format!("Value of controller {}", number), value))
+        },
+        Event::Midi(_, MidiEvent::ProgramChange(program)) => byte("Program", *program),
+        Event::Midi(_, MidiEvent::ChannelPressure(pressure)) => byte("Pressure", *pressure),
+        Event::Midi(_, MidiEvent::PitchWheelChange(value)) if *value > 0x3FFF => Some(format!("Pitch wheel value {} is above 16383", value)),
+        Event::Sysex(data) => data.iter().find(|byte| **byte > 0x7F).map(|byte| format!("System exclusive data contains the status byte {:02X}", byte)),
+        Event::Meta(MetaEvent::MidiChannelPrefix { channel }) if *channel > 0xF => Some(format!("Channel prefix {} is above 15", channel)),
+        Event::Meta(MetaEvent::MidiPort { port }) => byte("Port", *port),
+        Event::Meta(MetaEvent::KeySignature { sharps_flats, .. }) if !(-7..=7).contains(sharps_flats) => {
+            Some(format!("Key signature with {} sharps or flats", sharps_flats))
+        },
+        Event::Meta(MetaEvent::TimeSignature { numerator, denominator, .. }) if *numerator == 0 || !denominator.is_power_of_two() => {
+            Some(format!("Time signature {}/{} is not valid", numerator, denominator))
+        },
+        Event::Meta(MetaEvent::SetTempo { tempo }) if *tempo == 0 || *tempo > 0xFF_FFFF => Some(format!("Tempo {} does not fit into 1..16777215", tempo)),
+        _ => None
+    }
+}
+
+impl MidiFile {
+    // Checks the file for problems that players handle differently or not at all. The structure of
+    // the file is checked by the parser, its diagnostics are added with `LintReport::add_diagnostics`.
+    pub fn lint(&self) -> LintReport {
+        let mut report = LintReport::default();
+
+        for (track_index, (track, start)) in self.tracks.iter().zip(self.get_track_start_ticks()).enumerate() {
+            let mut tick = start;
+            for (dt, event) in track.events.iter() {
+                tick += *dt as u64;
+                if let Some(message) = out_of_range(event) {
+                    report.push(Finding::new(LintKind::DataOutOfRange, message).in_track(track_index).at_tick(tick));
+                }
+                if let Event::Meta(MetaEvent::SetTempo { .. }) = event {
+                    if self.header.format == Format::SimulTrack && track_index > 0 {
+                        report.push(Finding::new(LintKind::TempoOutsideConductor, "Tempo event outside of the first track").in_track(track_index).at_tick(tick));
+                    }
+                }
+            }
+        }
+
+        let notes = self.notes(NoteOverlap::Fifo);
+        let mut held_until: HashMap<(ChannelId, u8), u64> = HashMap::new(); // End of the last note per key
+        for note in notes.iter() {
+            let channel = note.channel_id();
+            if note.unterminated {
+                report.push(Finding::new(LintKind::UnterminatedNote, format!("Note on of key {} has no note off", note.key))
+                    .in_track(note.track).at_tick(note.start).on_channel(channel));
+            }
+            let held = held_until.entry((channel, note.key)).or_insert(0);
+            if note.start < *held {
+                report.push(Finding::new(LintKind::OverlappingNote, format!("Key {} is struck again while it is still held", note.key))
+                    .in_track(note.track).at_tick(note.start).on_channel(channel));
+            }
+            *held = (*held).max(note.end);
+        }
+
+        let playing = notes.iter().map(|note| note.channel_id()).collect::<BTreeSet<_>>();
+        let mut ports = PortTracker::new(self);
+        let mut unused = BTreeSet::new();
+        for (_, track, event) in self.merged_events() {
+            if let Some(channel) = ports.update(track, event) {
+                if !playing.contains(&channel) {
+                    unused.insert(channel);
+                }
+            }
+        }
+        for channel in unused {
+            report.push(Finding::new(LintKind::UnusedChannel, "Channel receives events but never plays a note").on_channel(channel));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::ParseOptions;
+
+    const NOTE: &[u8] = &[0x00, 0x90, 0x3C, 0x64, 0x60, 0x80, 0x3C, 0x40];
+    const END: &[u8] = &[0x00, 0xFF, 0x2F, 0x00];
+
+    fn smf(format: u16, ntrks: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut out = b"MThd".to_vec();
+        out.extend(6_u32.to_be_bytes());
+        out.extend(format.to_be_bytes());
+        out.extend(ntrks.to_be_bytes());
+        out.extend(96_u16.to_be_bytes());
+        for track in tracks {
+            out.extend(b"MTrk");
+            out.extend((track.len() as u32).to_be_bytes());
+            out.extend(*track);
+        }
+        out
+    }
+
+    fn track(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    fn lint(input: &[u8]) -> LintReport {
+        let (file, diagnostics) = MidiFile::from_bytes_with_options(input, &ParseOptions::lenient()).unwrap();
+        let mut report = file.lint();
+        report.add_diagnostics(&diagnostics);
+        report
+    }
+
+    fn names(report: &LintReport) -> Vec<&'static str> {
+        report.findings.iter().map(|finding| finding.kind.name()).collect()
+    }
+
+    #[test]
+    fn clean_file() {
+        assert_eq!(names(&lint(&smf(0, 1, &[&track(&[NOTE, END])]))), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn notes() {
+        let unterminated = track(&[&[0x00, 0x90, 0x3C, 0x64], END]);
+        assert_eq!(names(&lint(&smf(0, 1, &[&unterminated]))), ["unterminated-note"]);
+        let overlapping = track(&[&[0x00, 0x90, 0x3C, 0x64, 0x10, 0x90, 0x3C, 0x64, 0x10, 0x80, 0x3C, 0x40, 0x10, 0x80, 0x3C, 0x40], END]);
+        assert_eq!(names(&lint(&smf(0, 1, &[&overlapping]))), ["overlapping-note"]);
+    }
+
+    #[test]
+    fn end_of_track() {
+        let after_end = track(&[NOTE, END, &[0x00, 0x90, 0x3C, 0x64]]);
+        let report = lint(&smf(0, 1, &[&after_end]));
+        assert_eq!(names(&report), ["event-after-end-of-track"]);
+        assert_eq!(report.findings[0].track, Some(0));
+        assert_eq!(names(&lint(&smf(0, 1, &[NOTE]))), ["missing-end-of-track"]);
+    }
+
+    #[test]
+    fn track_count_mismatch() {
+        let report = lint(&smf(1, 2, &[&track(&[NOTE, END])]));
+        assert_eq!(names(&report), ["track-count-mismatch"]);
+        assert_eq!(report.findings[0].offset, None);
+        assert!(!report.findings[0].to_string().contains("byte"));
+    }
+
+    #[test]
+    fn junk_before_header() {
+        let mut input = vec![0, 0, 0];
+        input.extend(smf(0, 1, &[&track(&[NOTE, END])]));
+        let report = lint(&input);
+        assert_eq!(names(&report), ["junk-before-header"]);
+        assert_eq!(report.findings[0].offset, Some(0));
+    }
+
+    #[test]
+    fn unterminated_sysex() {
+        let sysex = track(&[&[0x00, 0xF0, 0x02, 0x41, 0x10], NOTE, END]);
+        assert_eq!(names(&lint(&smf(0, 1, &[&sysex]))), ["unterminated-sysex"]);
+    }
+
+    #[test]
+    fn data_out_of_range() {
+        let key_signature = track(&[&[0x00, 0xFF, 0x59, 0x02, 0x08, 0x00], NOTE, END]);
+        assert_eq!(names(&lint(&smf(0, 1, &[&key_signature]))), ["data-out-of-range"]);
+    }
+
+    #[test]
+    fn tempo_outside_conductor() {
+        let tempo = track(&[&[0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20], NOTE, END]);
+        let report = lint(&smf(1, 2, &[END, &tempo]));
+        assert_eq!(names(&report), ["tempo-outside-conductor"]);
+        assert_eq!(report.findings[0].track, Some(1));
+        // Format 0 and the first track of format 1 are fine
+        assert_eq!(names(&lint(&smf(1, 2, &[&tempo, END]))), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn unused_channel() {
+        let program_change = track(&[&[0x00, 0xC1, 0x05], NOTE, END]);
+        let report = lint(&smf(0, 1, &[&program_change]));
+        assert_eq!(names(&report), ["unused-channel"]);
+        assert_eq!(report.findings[0].channel, Some(ChannelId::new(0, 1)));
+    }
+}
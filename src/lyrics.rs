@@ -0,0 +1,216 @@
+
+use crate::midi_parser::{MidiFile, Event, MetaEvent};
+use crate::tempo_map::TempoMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Syllable {
+    pub text: String,
+    pub tick: u64, // Absolute ticks
+    pub seconds: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LyricLine {
+    pub syllables: Vec<Syllable>,
+}
+
+impl LyricLine {
+    pub fn text(&self) -> String {
+        self.syllables.iter().map(|syllable| syllable.text.as_str()).collect()
+    }
+
+    pub fn start_seconds(&self) -> Option<f64> {
+        self.syllables.first().map(|syllable| syllable.seconds)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Paragraph {
+    pub lines: Vec<LyricLine>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Lyrics {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub info: Vec<String>, // Remaining karaoke headers, e.g. copyright notes
+    pub paragraphs: Vec<Paragraph>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Break {
+    None,
+    Line,
+    Paragraph,
+}
+
+impl Lyrics {
+    fn start_break(&mut self, kind: Break) {
+        let last_paragraph_used = self.paragraphs.last().is_some_and(|paragraph| paragraph.lines.iter().any(|line| !line.syllables.is_empty()));
+        match kind {
+            Break::Paragraph if last_paragraph_used => self.paragraphs.push(Paragraph::default()),
+            Break::Line => {
+                if let Some(paragraph) = self.paragraphs.last_mut() {
+                    if paragraph.lines.last().is_some_and(|line| !line.syllables.is_empty()) {
+                        paragraph.lines.push(LyricLine::default());
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn push(&mut self, syllable: Syllable) {
+        if self.paragraphs.is_empty() {
+            self.paragraphs.push(Paragraph::default());
+        }
+        let paragraph = self.paragraphs.last_mut().unwrap();
+        if paragraph.lines.is_empty() {
+            paragraph.lines.push(LyricLine::default());
+        }
+        paragraph.lines.last_mut().unwrap().syllables.push(syllable);
+    }
+
+    // All lines in order, without the paragraph structure
+    pub fn lines(&self) -> impl Iterator<Item = &LyricLine> {
+        self.paragraphs.iter().flat_map(|paragraph| paragraph.lines.iter()).filter(|line| !line.syllables.is_empty())
+    }
+}
+
+// Karaoke files mark a new paragraph with '\' and a new line with '/' in front of a syllable.
+// In lyric events these characters are part of the text, they end a line with a carriage return
+// and a paragraph with a line feed.
+fn split_breaks(text: &str, is_karaoke: bool) -> (Break, &str, Break) {
+    let (before, text) = if !is_karaoke {
+        (Break::None, text)
+    } else if let Some(rest) = text.strip_prefix('\\') {
+        (Break::Paragraph, rest)
+    } else if let Some(rest) = text.strip_prefix('/') {
+        (Break::Line, rest)
+    } else {
+        (Break::None, text)
+    };
+    let after = if text.ends_with("\r\n") || text.ends_with('\r') {
+        Break::Line
+    } else if text.ends_with('\n') {
+        Break::Paragraph
+    } else {
+        Break::None
+    };
+    (before, text.trim_end_matches(['\r', '\n']), after)
+}
+
+impl MidiFile {
+    // Lyrics from the text events of karaoke (.kar) files or from lyric meta events
+    pub fn lyrics(&self) -> Lyrics {
+        let tempo_map = TempoMap::new(self);
+        let events = self.merged_events().filter_map(|(tick, _, event)| match event {
+            Event::Meta(MetaEvent::Lyric { text }) => Some((tick, true, text.as_str())),
+            Event::Meta(MetaEvent::Text { text }) => Some((tick, false, text.as_str())),
+            _ => None
+        }).collect::<Vec<_>>();
+        let has_lyric_events = events.iter().any(|(_, is_lyric, _)| *is_lyric);
+        // Karaoke files announce themselves with "@KMIDI KARAOKE FILE", but not all of them do
+        let is_karaoke = events.iter().any(|(_, is_lyric, text)| !is_lyric && (text.starts_with("@KMIDI") || (!has_lyric_events && text.starts_with('@'))));
+
+        let mut lyrics = Lyrics::default();
+        for (tick, is_lyric, text) in events {
+            if !is_lyric && is_karaoke {
+                // Karaoke headers: @T holds the title, then the author; @I further information
+                if let Some(header) = text.strip_prefix('@') {
+                    let value = header.get(1..).unwrap_or("").trim().to_string();
+                    match header.chars().next() {
+                        Some('T') if lyrics.title.is_none() => lyrics.title = Some(value),
+                        Some('T') if lyrics.author.is_none() => lyrics.author = Some(value),
+                        Some('T') | Some('I') => lyrics.info.push(value),
+                        _ => {}
+                    }
+                    continue;
+                }
+            }
+            // Karaoke files use text events for the lyrics, all other files lyric events
+            if is_lyric == is_karaoke {
+                continue;
+            }
+
+            let (before, syllable, after) = split_breaks(text, is_karaoke);
+            lyrics.start_break(before);
+            if !syllable.is_empty() {
+                lyrics.push(Syllable { text: syllable.to_string(), tick, seconds: tempo_map.ticks_to_seconds(tick) });
+            }
+            lyrics.start_break(after);
+        }
+        lyrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{HeaderChunk, TrackChunk, Format, Division};
+
+    fn text(dt: u32, text: &str) -> (u32, Event) {
+        (dt, Event::Meta(MetaEvent::Text { text: text.into() }))
+    }
+
+    fn lyric(dt: u32, text: &str) -> (u32, Event) {
+        (dt, Event::Meta(MetaEvent::Lyric { text: text.into() }))
+    }
+
+    fn file(mut events: Vec<(u32, Event)>) -> MidiFile {
+        events.push((0, Event::Meta(MetaEvent::EndOfTrack)));
+        let header = HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: Division::TicksPerQuarter(96) };
+        MidiFile { header, tracks: vec![TrackChunk { events }], riff_info: None }
+    }
+
+    // The text of every line, grouped by paragraph
+    fn paragraphs(lyrics: &Lyrics) -> Vec<Vec<String>> {
+        lyrics.paragraphs.iter()
+            .map(|paragraph| paragraph.lines.iter().filter(|line| !line.syllables.is_empty()).map(LyricLine::text).collect::<Vec<_>>())
+            .filter(|lines| !lines.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn lyric_events_keep_slashes() {
+        let lyrics = file(vec![lyric(0, "Rock "), lyric(48, "and/or "), lyric(48, "roll\r"), lyric(96, "\\o/\n"), lyric(96, "Next")]).lyrics();
+        assert_eq!(paragraphs(&lyrics), [vec!["Rock and/or roll", "\\o/"], vec!["Next"]]);
+        assert_eq!(lyrics.paragraphs[0].lines[0].syllables[1], Syllable { text: String::from("and/or "), tick: 48, seconds: 0.25 });
+    }
+
+    #[test]
+    fn soft_karaoke_breaks() {
+        let lyrics = file(vec![
+            text(0, "@KMIDI KARAOKE FILE"),
+            text(0, "@TSong"),
+            text(0, "@TSinger"),
+            text(0, "@IFree"),
+            text(0, "\\Hel"),
+            text(48, "lo "),
+            text(48, "/world"),
+            text(96, "\\Bye"),
+        ]).lyrics();
+        assert_eq!(lyrics.title.as_deref(), Some("Song"));
+        assert_eq!(lyrics.author.as_deref(), Some("Singer"));
+        assert_eq!(lyrics.info, ["Free"]);
+        assert_eq!(paragraphs(&lyrics), [vec!["Hello ", "world"], vec!["Bye"]]);
+    }
+
+    #[test]
+    fn karaoke_text_wins_over_lyric_events() {
+        // Some karaoke files carry lyric events as well, which would show every syllable twice
+        let lyrics = file(vec![
+            text(0, "@KMIDI KARAOKE FILE"),
+            text(0, "\\Hel"),
+            lyric(0, "Hel"),
+            text(48, "/lo"),
+            lyric(0, "lo"),
+        ]).lyrics();
+        assert_eq!(paragraphs(&lyrics), [vec!["Hel", "lo"]]);
+
+        // Without the announcement text events with a leading @ do not make a karaoke file
+        let lyrics = file(vec![text(0, "@Comment"), lyric(0, "/Hel"), lyric(48, "lo")]).lyrics();
+        assert_eq!(lyrics.title, None);
+        assert_eq!(paragraphs(&lyrics), [vec!["/Hello"]]);
+    }
+}
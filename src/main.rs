@@ -4,6 +4,26 @@ use raylib::prelude::*;
 use raylib::core::logging::set_trace_log;
 
 mod midi_parser;
+mod midi_writer;
+mod riff;
+mod text_encoding;
+mod shift_jis_table;
+mod timecode;
+mod tempo_map;
+mod meter_map;
+mod notes;
+mod merged_events;
+mod ports;
+mod parameters;
+mod channel_state;
+mod instrument_names;
+mod patches;
+mod convert;
+mod transform;
+mod quantize;
+mod lyrics;
+mod lint;
+mod cli;
 mod audio_generator;
 
 
@@ -87,19 +107,14 @@ const COLORS: [Color; 8] = [
     Color::SKYBLUE
 ];
 
-fn get_color(channel: u8) -> Color {
-    COLORS[channel as usize % COLORS.len()]
-}
-
-#[derive(Debug,Clone,Copy)]
-struct TrackPlayer {
-    event_pointer: usize
-}
-
-impl TrackPlayer {
-    fn new() -> Self {
-        Self { event_pointer: 0 }
+// Channels on other ports get the colour of their channel with a shifted hue
+fn get_color(channel: ChannelId) -> Color {
+    let color = COLORS[channel.channel as usize % COLORS.len()];
+    if channel.port == 0 {
+        return color;
     }
+    let hsv = color.color_to_hsv();
+    Color::color_from_hsv((hsv.x + channel.port as f32 * 23.0) % 360.0, hsv.y, hsv.z)
 }
 
 use std::env;
@@ -111,11 +126,10 @@ enum State {
 }
 
 struct NoteVisual {
-    channel: u8,
+    channel: ChannelId,
     key: u8,
-    track: usize,
     start_time: f64,
-    stop_time: Option<f64>,
+    stop_time: f64,
     rect: Option<Rectangle>,
 }
 
@@ -129,16 +143,17 @@ fn compute_time_scale(rl: &RaylibHandle) -> f64 {
 }
 
 impl NoteVisual {
-    fn new(channel: u8, key: u8, track: usize, start_time: f64) -> Self { 
-        Self { channel, key, track, stop_time: None, start_time, rect: None }
+    fn new(channel: ChannelId, key: u8, start_time: f64, stop_time: f64) -> Self {
+        Self { channel, key, stop_time, start_time, rect: None }
     }
 
     fn get_rect(&self, rl: &RaylibHandle, curr_time: f64, keyboard_bounds: Rectangle) -> Rectangle {
         let keyboard_key_rect = key_rect(self.key, keyboard_bounds);
         let time_scale = compute_time_scale(rl);
-        let start_y = match self.stop_time {
-            Some(t) => (curr_time - t) * time_scale,
-            None => 0.0
+        let start_y = if curr_time > self.stop_time {
+            (curr_time - self.stop_time) * time_scale
+        } else {
+            0.0
         };
         let end_y = (curr_time - self.start_time) * time_scale;
         Rectangle::new(keyboard_key_rect.x, start_y as f32, keyboard_key_rect.width, (end_y - start_y) as f32)
@@ -167,60 +182,150 @@ impl NoteVisual {
     }
 }
 
-use audio_generator::{NormalizedTrack, NormalizedEvent};
+// Draws a line at the start of every bar that is currently falling towards the keyboard
+fn draw_measure_lines(d: &mut RaylibDrawHandle, meter_map: &MeterMap, tempo_map: &TempoMap, played_time: f64, keyboard_bounds: Rectangle) {
+    let time_scale = compute_time_scale(d);
+    let mut bar = meter_map.seconds_to_position(tempo_map, played_time).bar;
+    loop {
+        let bar_time = meter_map.position_to_seconds(tempo_map, BarBeatTick::new(bar, 1, 0));
+        if bar_time > played_time + TIME_OFFSET {
+            break;
+        }
+        let y = keyboard_bounds.y as f64 - (bar_time - played_time) * time_scale;
+        if y <= keyboard_bounds.y as f64 {
+            d.draw_line(0, y as i32, keyboard_bounds.width as i32, y as i32, Color::DARKGRAY);
+        }
+        bar += 1;
+    }
+}
+
+const LEGEND_FONT_SIZE: i32 = 20;
+const LEGEND_WIDTH: i32 = 380;
+const LEGEND_ROWS: usize = 16;
 
-fn update_track_players(track_players: &mut Vec<TrackPlayer>, normed_tracks: &Vec<NormalizedTrack>, elapsed_time: f64, note_visuals: &mut Vec<NoteVisual>) {
-    'outer: for (i, player) in track_players.iter_mut().enumerate() {
-                    
-        if player.event_pointer >= normed_tracks[i].events.len() {
-            continue 'outer;
+// Pan position and pressed pedals of a channel, e.g. "L20 Ped"
+fn channel_controls(state: &ChannelState) -> String {
+    let mut controls = match state.pan() {
+        64 => vec![],
+        pan if pan < 64 => vec![format!("L{}", 64 - pan)],
+        pan => vec![format!("R{}", pan - 64)],
+    };
+    for (pressed, name) in [(state.damper_pedal(), "Ped"), (state.sostenuto_pedal(), "Sost"), (state.soft_pedal(), "Soft")] {
+        if pressed {
+            controls.push(name.to_string());
         }
+    }
+    controls.join(" ")
+}
 
-        while elapsed_time > normed_tracks[i].events[player.event_pointer].0 {
-            match normed_tracks[i].events[player.event_pointer].1 {
-                NormalizedEvent::KeyOff { key, channel, .. } => {
-                    //key_map[key as usize] = None;
-                    for nv in note_visuals.iter_mut() {
-                        if nv.track == i && nv.channel == channel && nv.key == key && nv.stop_time.is_none() {
-                            nv.stop_time = Some(elapsed_time);
-                            break;
-                        }
-                    }
-                },
-                NormalizedEvent::KeyOn { key, channel, .. } => {
-                    //let color = COLORS[channel as usize % COLORS.len()];
-                    //key_map[key as usize] = Some(color);
-                    note_visuals.push(NoteVisual::new(channel, key, i, elapsed_time));
-                },
-            }
-            player.event_pointer += 1;
-            if player.event_pointer >= normed_tracks[i].events.len() {
-                continue 'outer;
-            }
-        
+// Lists the channels in the top right corner with their colour, the instrument they play at the moment, their pan and pressed pedals
+fn draw_channel_legend(d: &mut RaylibDrawHandle, channels: &[ChannelId], patch_map: &PatchMap, states: &ChannelStates, tick: u64) {
+    let x = d.get_screen_width() - LEGEND_WIDTH;
+    for (row, channel) in channels.iter().take(LEGEND_ROWS).enumerate() {
+        let y = 23 + row as i32 * (LEGEND_FONT_SIZE + 7);
+        d.draw_rectangle(x, y, LEGEND_FONT_SIZE, LEGEND_FONT_SIZE, get_color(*channel));
+        d.draw_text(&format!("{} {}", channel, patch_map.name_at(*channel, tick)), x + LEGEND_FONT_SIZE + 7, y, LEGEND_FONT_SIZE, Color::WHITE);
+        let controls = channel_controls(states.get(*channel));
+        d.draw_text(&controls, d.get_screen_width() - 7 - measure_text(&controls, LEGEND_FONT_SIZE), y, LEGEND_FONT_SIZE, Color::GRAY);
+    }
+}
+
+const LYRICS_FONT_SIZE: i32 = 30;
+
+// Shows the current and the next line of the lyrics, already sung syllables are highlighted
+fn draw_lyrics(d: &mut RaylibDrawHandle, lines: &[&LyricLine], played_time: f64) {
+    let current = lines.iter().rposition(|line| line.start_seconds().is_some_and(|start| start <= played_time)).unwrap_or(0);
+    for (row, line) in lines.iter().skip(current).take(2).enumerate() {
+        let y = 110 + row as i32 * (LYRICS_FONT_SIZE + 10);
+        let mut x = d.get_screen_width() / 2 - measure_text(&line.text(), LYRICS_FONT_SIZE) / 2;
+        for syllable in line.syllables.iter() {
+            let color = if syllable.seconds <= played_time { Color::GOLD } else { Color::WHITE };
+            d.draw_text(&syllable.text, x, y, LYRICS_FONT_SIZE, color);
+            x += measure_text(&syllable.text, LYRICS_FONT_SIZE);
         }
-        
+    }
+}
+
+fn update_note_visuals(notes: &[Note], note_pointer: &mut usize, elapsed_time: f64, note_visuals: &mut Vec<NoteVisual>) {
+    while *note_pointer < notes.len() && elapsed_time > notes[*note_pointer].start_seconds {
+        let note = &notes[*note_pointer];
+        // Notes that already ended when playback starts later in the file are not shown
+        if note.end_seconds + TIME_OFFSET > elapsed_time {
+            note_visuals.push(NoteVisual::new(note.channel_id(), note.key, note.start_seconds, note.end_seconds));
+        }
+        *note_pointer += 1;
     }
 }
 
 use std::thread;
 use std::sync::Mutex;
 use audio_generator::{ProgressInfo, generate_audio};
-use midi_parser::{Format, MidiFile};
+use midi_parser::Division;
+use timecode::Timecode;
+use tempo_map::TempoMap;
+use meter_map::{MeterMap, BarBeatTick};
+use notes::Note;
+use lyrics::LyricLine;
+use ports::ChannelId;
+use patches::PatchMap;
+use channel_state::{ChannelState, ChannelStates, ChannelTimeline};
 
 fn main() {
-    let args = env::args().collect::<Vec<_>>();
-    if args.len() < 3 {
-        eprintln!("usage: {} [input] [output]", args[0]);
+    let mut args = env::args().collect::<Vec<_>>();
+    let text_encoding = match cli::take_encoding(&mut args) {
+        Ok(text_encoding) => text_encoding,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    if args.len() >= 2 && args[1] == "convert" {
+        if !cli::convert(&args[0], &args[2..], text_encoding) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.len() >= 2 && args[1] == "lint" {
+        if !cli::lint(&args[0], &args[2..], text_encoding) {
+            std::process::exit(1);
+        }
         return;
     }
+    if args.len() < 3 {
+        eprintln!("usage: {} [input] [output] [--lifo] [--start SECONDS] [transformations]", args[0]);
+        eprintln!("       {} convert [0|1] [input] [output] [transformations]", args[0]);
+        eprintln!("       {} lint [--json] [--strict] [input...]", args[0]);
+        eprintln!("options:\n    --encoding NAME           fallback for texts that are not UTF-8: auto, cp1252, latin1, shift-jis or utf-8");
+        eprintln!("{}", cli::TRANSFORM_USAGE);
+        std::process::exit(1);
+    }
+    let mut render_args = args[3..].to_vec();
+    let render_options = match cli::take_render_options(&mut render_args) {
+        Ok(render_options) => render_options,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    let transforms = match cli::parse_transforms(&render_args) {
+        Ok(transforms) => transforms,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
 
     let file =  Arc::new({
-        match MidiFile::read_midi(&args[1]) {
-            Ok(file) => file,
+        match cli::read_input(&args[1], text_encoding) {
+            Ok(mut file) => {
+                for transform in transforms.iter() {
+                    file.apply(transform);
+                }
+                file
+            },
             Err(err) => {
                 eprintln!("{}", err);
-                return;
+                std::process::exit(1);
             }
         }
     });
@@ -232,9 +337,8 @@ fn main() {
         let file_pointer = Arc::clone(&file);
         let wav_file_path = wav_file_path.clone();
         let progress_info_pointer = Arc::clone(&progress_info);
-        thread::spawn(move || generate_audio(file_pointer, &wav_file_path, progress_info_pointer));
+        thread::spawn(move || generate_audio(file_pointer, &wav_file_path, render_options, progress_info_pointer));
     }
-    assert!(file.header.format != Format::SequenceTrack);
 
     const WINDOW_WIDTH: i32 = 1280;
     const WINDOW_HEIGHT: i32 = 720;
@@ -242,7 +346,13 @@ fn main() {
 
 
     set_trace_log(TraceLogLevel::LOG_NONE);
-    let (mut rl, thread) = raylib::init().width(WINDOW_WIDTH).height(WINDOW_HEIGHT).title("Mididi").resizable().build();
+    let lyrics = file.lyrics();
+    let lyric_lines = lyrics.lines().collect::<Vec<_>>();
+    let title = match file.riff_info.as_ref().and_then(|info| info.title.as_ref()).or(lyrics.title.as_ref()) {
+        Some(song_title) => format!("Mididi - {}", song_title),
+        None => String::from("Mididi")
+    };
+    let (mut rl, thread) = raylib::init().width(WINDOW_WIDTH).height(WINDOW_HEIGHT).title(&title).resizable().build();
     
 
     let mut rl_audio = RaylibAudio::init_audio_device();
@@ -251,8 +361,15 @@ fn main() {
     rl.set_exit_key(None);
     rl.set_target_fps(FPS);
     
-    let simult_tracks = file.get_simult_track_count(); 
-    let mut track_players = vec![TrackPlayer::new(); simult_tracks as usize];
+    let tempo_map = TempoMap::new(&file);
+    let meter_map = MeterMap::new(&file, &tempo_map);
+    let notes = file.notes(render_options.note_overlap);
+    let mut note_pointer = 0;
+    let patch_map = PatchMap::new(&file);
+    let channel_timeline = ChannelTimeline::new(&file);
+    let mut used_channels = notes.iter().map(|note| note.channel_id()).collect::<Vec<_>>();
+    used_channels.sort();
+    used_channels.dedup();
     
     let mut key_map  = [None; 128];
     let mut note_visuals: Vec<NoteVisual> = Vec::new();
@@ -266,16 +383,13 @@ fn main() {
         match state {
             State::VISUALIZING => {
                 
-                let pi = progress_info.lock().unwrap();
                 let music = music.as_mut().unwrap();
-                let normed_tracks = pi.result.as_ref().unwrap();
-                
-                
 
                 rl_audio.update_music_stream(music);
-                let elapsed_time = rl_audio.get_music_time_played(music) as f64 + TIME_OFFSET;
+                let played_time = rl_audio.get_music_time_played(music) as f64 + render_options.start_seconds;
+                let elapsed_time = played_time + TIME_OFFSET;
                 
-                update_track_players(&mut track_players, &normed_tracks, elapsed_time, &mut note_visuals);
+                update_note_visuals(&notes, &mut note_pointer, elapsed_time, &mut note_visuals);
                 
                 let initial_note_visual_count = note_visuals.len();
                 for i in 0..initial_note_visual_count {
@@ -298,11 +412,31 @@ fn main() {
                 let mut d = rl.begin_drawing(&thread);
                 d.clear_background(Color::BLACK);
                 
+                if let Some(meter_map) = &meter_map {
+                    draw_measure_lines(&mut d, meter_map, &tempo_map, played_time, key_board_bounds);
+                }
                 for key in note_visuals.iter_mut() {
                     key.render(elapsed_time, &mut d, key_board_bounds)
                 }
                 draw_keyboard(&mut d, key_board_bounds, key_map);
+                draw_lyrics(&mut d, &lyric_lines, played_time);
+                draw_channel_legend(&mut d, &used_channels, &patch_map, &channel_timeline.states_at_seconds(played_time), tempo_map.seconds_to_ticks(played_time));
                 d.draw_text(&format!("{}", fps), 23,23, 23, Color::WHITE);
+                match file.header.division {
+                    Division::TicksPerFrame(frame_rate, _) => {
+                        d.draw_text(&Timecode::from_seconds(played_time, frame_rate).to_string(), 23, 50, 23, Color::WHITE);
+                    },
+                    Division::TicksPerQuarter(_) => {
+                        let tick = tempo_map.seconds_to_ticks(played_time);
+                        let tempo = tempo_map.tempo_at(tick);
+                        d.draw_text(&format!("{:.1} BPM", 60_000_000.0 / tempo as f64), 23, 50, 23, Color::WHITE);
+                        if let Some(meter_map) = &meter_map {
+                            let position = meter_map.ticks_to_position(tick);
+                            let (numerator, denominator) = meter_map.time_signature_at(tick);
+                            d.draw_text(&format!("Bar {}  {}/{}", position, numerator, denominator), 23, 77, 23, Color::WHITE);
+                        }
+                    }
+                }
 
             },
             State::RENDERING => {
@@ -314,7 +448,7 @@ fn main() {
                         return;
                     }
 
-                    if let Some(normed_tracks) = &pi.result {
+                    if pi.finished {
                         state = State::VISUALIZING;
                         music = Some({
                             match Music::load_music_stream(&thread, &wav_file_path) {
@@ -326,10 +460,6 @@ fn main() {
                             }
                         });
                         rl_audio.play_music_stream(&mut music.as_mut().unwrap());
-                        let frames = (TIME_OFFSET * FPS as f64) as usize;
-                        for frame in 0..frames {
-                            update_track_players(&mut track_players, normed_tracks, frame as f64 / FPS as f64, &mut note_visuals);
-                        }
                     }
                 }
 
@@ -341,10 +471,10 @@ fn main() {
 
                 {
                     let pi = progress_info.lock().unwrap();
-                    d.draw_text(&format!("Track: {}/{}, Progress: {}", pi.track, file.header.ntrks, pi.track_progress), 23, 23, 23, Color::WHITE);
+                    d.draw_text(&format!("Progress: {:.1}%", pi.progress * 100.0), 23, 23, 23, Color::WHITE);
                     let mut progress_rect = Rectangle::new(d.get_screen_width() as f32 / 2.0 - PROGRESS_RECT_WIDTH / 2.0, d.get_screen_height() as f32 / 2.0 - PROGRESS_RECT_HEIGHT / 2.0, PROGRESS_RECT_WIDTH, PROGRESS_RECT_HEIGHT);
                     d.draw_rectangle_rec(progress_rect, Color::GRAY);
-                    progress_rect.width *= pi.track_progress as f32;
+                    progress_rect.width *= pi.progress as f32;
                     d.draw_rectangle_rec(progress_rect, Color::GREEN);
                 }
             }
@@ -0,0 +1,81 @@
+
+use crate::midi_parser::{MidiFile, Event, MidiEvent, MetaEvent};
+
+// Order of events that happen on the same tick. Note offs come before note ons so that a key
+// which is released and struck again on the same tick is not cut off. Note ons and the other
+// channel events keep their order, so that a program change after a note on does not change the note.
+// End of Track comes last, as the other events of its tick still belong to the track.
+fn tie_break_rank(event: &Event) -> u8 {
+    match event {
+        Event::Meta(MetaEvent::EndOfTrack) => 4,
+        Event::Meta(_) => 0,
+        Event::Sysex(_) | Event::Escape(_) => 1,
+        Event::Midi(_, MidiEvent::NoteOff { .. }) | Event::Midi(_, MidiEvent::NoteOn { velocity: 0, .. }) => 2,
+        Event::Midi(..) => 3,
+    }
+}
+
+// All events of a file in time order as (absolute tick, track index, event). Events on the
+// same tick are ordered by kind, then by track and then by their position inside the track.
+pub struct MergedEvents<'a> {
+    file: &'a MidiFile,
+    order: std::vec::IntoIter<(u64, u8, usize, usize)>, // (tick, rank, track, event index)
+}
+
+impl<'a> Iterator for MergedEvents<'a> {
+    type Item = (u64, usize, &'a Event);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.order.next().map(|(tick, _, track, index)| (tick, track, &self.file.tracks[track].events[index].1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.order.size_hint()
+    }
+}
+
+impl ExactSizeIterator for MergedEvents<'_> {}
+
+impl MidiFile {
+    pub fn merged_events(&self) -> MergedEvents<'_> {
+        let mut order = Vec::with_capacity(self.tracks.iter().map(|track| track.events.len()).sum());
+        for (track_index, (track, start)) in self.tracks.iter().zip(self.get_track_start_ticks()).enumerate() {
+            let mut tick = start;
+            for (event_index, (dt, event)) in track.events.iter().enumerate() {
+                tick += *dt as u64;
+                order.push((tick, tie_break_rank(event), track_index, event_index));
+            }
+        }
+        order.sort_unstable();
+        MergedEvents { file: self, order: order.into_iter() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{HeaderChunk, TrackChunk, Format, Division};
+
+    #[test]
+    fn end_of_track_is_the_last_event_of_its_tick() {
+        let track = |events| TrackChunk { events };
+        let header = HeaderChunk { format: Format::SimulTrack, ntrks: 2, division: Division::TicksPerQuarter(96) };
+        let file = MidiFile { header, tracks: vec![
+            track(vec![(96, Event::Meta(MetaEvent::EndOfTrack))]),
+            track(vec![
+                (96, Event::Midi(0, MidiEvent::NoteOn { key: 60, velocity: 100 })),
+                (0, Event::Meta(MetaEvent::SetTempo { tempo: 400_000 })),
+                (0, Event::Midi(0, MidiEvent::NoteOff { key: 62, velocity: 64 })),
+                (0, Event::Meta(MetaEvent::EndOfTrack)),
+            ]),
+        ], riff_info: None };
+        let events = file.merged_events().map(|(tick, track, event)| (tick, track, event.clone())).collect::<Vec<_>>();
+        assert_eq!(events, [
+            (96, 1, Event::Meta(MetaEvent::SetTempo { tempo: 400_000 })),
+            (96, 1, Event::Midi(0, MidiEvent::NoteOff { key: 62, velocity: 64 })),
+            (96, 1, Event::Midi(0, MidiEvent::NoteOn { key: 60, velocity: 100 })),
+            (96, 0, Event::Meta(MetaEvent::EndOfTrack)),
+            (96, 1, Event::Meta(MetaEvent::EndOfTrack)),
+        ]);
+    }
+}
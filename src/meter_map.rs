@@ -0,0 +1,165 @@
+
+use std::fmt::Display;
+
+use crate::midi_parser::{MidiFile, Format, Division, Event, MetaEvent};
+use crate::tempo_map::TempoMap;
+
+// A musical position. Bars and beats are counted from 1 like in notation software.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BarBeatTick {
+    pub bar: u32,
+    pub beat: u32,
+    pub tick: u32,
+}
+
+impl BarBeatTick {
+    pub fn new(bar: u32, beat: u32, tick: u32) -> Self {
+        Self { bar, beat, tick }
+    }
+}
+
+impl Display for BarBeatTick {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{:03}", self.bar, self.beat, self.tick)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MeterSegment {
+    tick: u64,
+    bar: u32, // Index of the first bar of the segment, starting at 0
+    numerator: u8,
+    denominator: u8,
+}
+
+// Converts between absolute ticks and bar:beat:tick positions, driven by the time signature events.
+// Absolute ticks are laid out like in `TempoMap`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeterMap {
+    ticks_per_quarter: u64,
+    segments: Vec<MeterSegment>,
+}
+
+impl MeterMap {
+    // Musical time is only defined for files whose division counts ticks per quarter note
+    pub fn new(file: &MidiFile, tempo_map: &TempoMap) -> Option<Self> {
+        let ticks_per_quarter = match file.header.division {
+            Division::TicksPerQuarter(ticks) => ticks as u64,
+            Division::TicksPerFrame(..) => return None,
+        };
+
+        let mut changes = vec![(0, 4, 4)];
+        for (track_index, track) in file.tracks.iter().enumerate() {
+            let mut tick = tempo_map.track_start(track_index);
+            if file.header.format == Format::SequenceTrack {
+                // Every pattern starts with a new bar in 4/4
+                changes.push((tick, 4, 4));
+            }
+            for (dt, event) in track.events.iter() {
+                tick += *dt as u64;
+                if let Event::Meta(MetaEvent::TimeSignature { numerator, denominator, .. }) = event {
+                    changes.push((tick, *numerator, *denominator));
+                }
+            }
+        }
+        // Stable sort: of several changes on the same tick the last one wins
+        changes.sort_by_key(|(tick, _, _)| *tick);
+
+        let mut map = Self { ticks_per_quarter, segments: Vec::with_capacity(changes.len()) };
+        for (tick, numerator, denominator) in changes {
+            let (numerator, denominator) = (numerator.max(1), denominator.max(1));
+            if let Some(last) = map.segments.last_mut().filter(|last| last.tick == tick) {
+                last.numerator = numerator;
+                last.denominator = denominator;
+                continue;
+            }
+            let bar = match map.segments.last() {
+                Some(last) => {
+                    // A change in the middle of a bar cuts it short and starts a new one
+                    let bar_ticks = map.bar_ticks(last);
+                    last.bar + (tick - last.tick).div_ceil(bar_ticks) as u32
+                },
+                None => 0
+            };
+            map.segments.push(MeterSegment { tick, bar, numerator, denominator });
+        }
+        Some(map)
+    }
+
+    fn beat_ticks(&self, segment: &MeterSegment) -> u64 {
+        (self.ticks_per_quarter * 4 / segment.denominator as u64).max(1)
+    }
+
+    fn bar_ticks(&self, segment: &MeterSegment) -> u64 {
+        self.beat_ticks(segment) * segment.numerator as u64
+    }
+
+    // Time signature (numerator, denominator) at a tick
+    pub fn time_signature_at(&self, tick: u64) -> (u8, u8) {
+        let segment = self.segment_at_tick(tick);
+        (segment.numerator, segment.denominator)
+    }
+
+    fn segment_at_tick(&self, tick: u64) -> &MeterSegment {
+        let index = self.segments.partition_point(|segment| segment.tick <= tick);
+        &self.segments[index.saturating_sub(1)]
+    }
+
+    fn segment_at_bar(&self, bar: u32) -> &MeterSegment {
+        let index = self.segments.partition_point(|segment| segment.bar <= bar);
+        &self.segments[index.saturating_sub(1)]
+    }
+
+    pub fn ticks_to_position(&self, tick: u64) -> BarBeatTick {
+        let segment = self.segment_at_tick(tick);
+        let offset = tick - segment.tick;
+        let bar_ticks = self.bar_ticks(segment);
+        let beat_ticks = self.beat_ticks(segment);
+        let bar = segment.bar + (offset / bar_ticks) as u32;
+        let in_bar = offset % bar_ticks;
+        BarBeatTick::new(bar + 1, (in_bar / beat_ticks) as u32 + 1, (in_bar % beat_ticks) as u32)
+    }
+
+    pub fn position_to_ticks(&self, position: BarBeatTick) -> u64 {
+        self.bar_start(position.bar) + (position.beat.saturating_sub(1) as u64) * self.beat_ticks(self.segment_at_bar(position.bar.saturating_sub(1))) + position.tick as u64
+    }
+
+    // Absolute tick at which a bar (counted from 1) starts
+    pub fn bar_start(&self, bar: u32) -> u64 {
+        let bar = bar.saturating_sub(1);
+        let segment = self.segment_at_bar(bar);
+        segment.tick + (bar - segment.bar) as u64 * self.bar_ticks(segment)
+    }
+
+    pub fn seconds_to_position(&self, tempo_map: &TempoMap, seconds: f64) -> BarBeatTick {
+        self.ticks_to_position(tempo_map.seconds_to_ticks(seconds))
+    }
+
+    pub fn position_to_seconds(&self, tempo_map: &TempoMap, position: BarBeatTick) -> f64 {
+        tempo_map.ticks_to_seconds(self.position_to_ticks(position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{HeaderChunk, TrackChunk};
+
+    #[test]
+    fn change_in_the_middle_of_a_bar_starts_a_new_one() {
+        let time_signature = |numerator, denominator| Event::Meta(MetaEvent::TimeSignature { numerator, denominator, metronome_clocks: 24, notated_32s_per_quarter: 8 });
+        let events = vec![(0, time_signature(4, 4)), (2880, time_signature(6, 8)), (0, Event::Meta(MetaEvent::EndOfTrack))];
+        let header = HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: Division::TicksPerQuarter(480) };
+        let file = MidiFile { header, tracks: vec![TrackChunk { events }], riff_info: None };
+        let map = MeterMap::new(&file, &TempoMap::new(&file)).unwrap();
+
+        assert_eq!(map.ticks_to_position(2879), BarBeatTick::new(2, 2, 479));
+        assert_eq!(map.ticks_to_position(2880), BarBeatTick::new(3, 1, 0));
+        assert_eq!(map.ticks_to_position(2880 + 1440), BarBeatTick::new(4, 1, 0));
+        assert_eq!(map.time_signature_at(2879), (4, 4));
+        assert_eq!(map.time_signature_at(2880), (6, 8));
+        for tick in (0..10000).step_by(13) {
+            assert_eq!(map.position_to_ticks(map.ticks_to_position(tick)), tick);
+        }
+    }
+}
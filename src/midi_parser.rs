@@ -1,9 +1,12 @@
 
-use std::io::Read;
+use std::io::{Read, BufReader, ErrorKind};
 use std::fmt::Display;
 use std::str;
 use std::fs::File;
 
+use crate::riff::{RiffInfo, is_riff, unwrap_rmid};
+use crate::text_encoding::{MetaText, TextEncoding};
+
 #[derive(Debug, Clone,Copy,PartialEq, Eq)]
 pub enum Format {
     SingleTrack,
@@ -17,132 +20,530 @@ pub enum Division {
     TicksPerFrame(u32,u32) // (FPS,ticks per frame)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MetaEvent {
-    SequenceTrackName { text: String },
-    SetTempo { tempo: u32 },
-    TimeSignature { denominator: u8, numerator: u8, metronome_clocks: u8, notated_32s_per_quarter: u8 },
-    Text { text: String },
+    SequenceNumber { number: Option<u16> }, // None: the position of the track in the file is used
+    Text { text: MetaText },
+    CopyrightNotice { text: MetaText },
+    SequenceTrackName { text: MetaText },
+    InstrumentName { text: MetaText },
+    Lyric { text: MetaText },
+    Marker { text: MetaText },
+    CuePoint { text: MetaText },
+    ProgramName { text: MetaText },
+    DeviceName { text: MetaText },
+    MidiChannelPrefix { channel: u8 },
+    MidiPort { port: u8 },
     EndOfTrack,
-    Unknown
+    SetTempo { tempo: u32 },
+    SmpteOffset { fps: u8, hours: u8, minutes: u8, seconds: u8, frames: u8, fractional_frames: u8 },
+    TimeSignature { numerator: u8, denominator: u8, metronome_clocks: u8, notated_32s_per_quarter: u8 }, // denominator: actual note value (e.g. 8 for x/8)
+    KeySignature { sharps_flats: i8, minor: bool }, // sharps_flats: negative values count flats
+    SequencerSpecific { data: Vec<u8> },
+    Unknown { meta_type: u8, data: Vec<u8> }
 }
 
 #[derive(Debug, Clone,Copy,PartialEq,Eq)]
 pub enum ControllerMessage {
-    ResetAllControllers,
     BankSelectMSB(u8),
-    BankSelectLSB(u8),
-    ChannelVolumeMSB(u8),
-    PanMSB(u8),
-    ExpressionControllerMSB(u8),
-    EffectsDepth1LSB(u8),
-    EffectsDepth3LSB(u8),
-    RegisteredParameterNumberMSB(u8),
-    RegisteredParameterNumberLSB(u8),
-    NonRegisteredParameterNumberMSB(u8),
-    NonRegisteredParameterNumberLSB(u8),
-    DataEntryMSB(u8),
-    DamperPedalOn(bool),
-    AllSoundOff,
-    AllNotesOff,
     ModulationWheel(u8),
     BreathControlMSB(u8),
-    PortamentoOnOff(bool),
+    FootControllerMSB(u8),
     PortamentoTimeMSB(u8),
-    PolyModeOnOffAllNotesOff,
+    DataEntryMSB(u8),
+    ChannelVolumeMSB(u8),
+    BalanceMSB(u8),
+    PanMSB(u8),
+    ExpressionControllerMSB(u8),
+    EffectControl1MSB(u8),
+    EffectControl2MSB(u8),
     GeneralPurposeController1MSB(u8),
+    GeneralPurposeController2MSB(u8),
+    GeneralPurposeController3MSB(u8),
+    GeneralPurposeController4MSB(u8),
+    BankSelectLSB(u8),
+    ModulationWheelLSB(u8),
+    BreathControlLSB(u8),
+    FootControllerLSB(u8),
+    PortamentoTimeLSB(u8),
     DataEntryLSB(u8),
+    ChannelVolumeLSB(u8),
+    BalanceLSB(u8),
+    PanLSB(u8),
+    ExpressionControllerLSB(u8),
+    EffectControl1LSB(u8),
+    EffectControl2LSB(u8),
+    GeneralPurposeController1LSB(u8),
+    GeneralPurposeController2LSB(u8),
+    GeneralPurposeController3LSB(u8),
+    GeneralPurposeController4LSB(u8),
+    DamperPedalOn(u8),
+    PortamentoOnOff(u8),
+    SostenutoOnOff(u8),
+    SoftPedalOnOff(u8),
+    LegatoFootswitch(u8),
+    Hold2(u8),
+    SoundVariation(u8),
+    HarmonicIntensity(u8),
+    ReleaseTime(u8),
+    AttackTime(u8),
+    Brightness(u8),
+    DecayTime(u8),
+    VibratoRate(u8),
+    VibratoDepth(u8),
+    VibratoDelay(u8),
+    GeneralPurposeController5(u8),
+    GeneralPurposeController6(u8),
+    GeneralPurposeController7(u8),
+    GeneralPurposeController8(u8),
+    PortamentoControl(u8),
+    HighResolutionVelocityPrefix(u8),
+    EffectsDepth1(u8), // Reverb send
+    EffectsDepth2(u8), // Tremolo
+    EffectsDepth3(u8), // Chorus send
+    EffectsDepth4(u8), // Celeste
+    EffectsDepth5(u8), // Phaser
+    DataIncrement(u8),
+    DataDecrement(u8),
+    NonRegisteredParameterNumberLSB(u8),
+    NonRegisteredParameterNumberMSB(u8),
+    RegisteredParameterNumberLSB(u8),
+    RegisteredParameterNumberMSB(u8),
+    AllSoundOff(u8),
+    ResetAllControllers(u8),
+    LocalControl(u8),
+    AllNotesOff(u8),
+    OmniModeOff(u8),
+    OmniModeOn(u8),
+    PolyModeOnOffAllNotesOff(u8), // Mono mode on, the value is the number of channels
+    PolyModeOn(u8),
+    Other { number: u8, value: u8 }
+}
+
+impl ControllerMessage {
+    pub fn from_raw(number: u8, value: u8) -> Self {
+        match number {
+            0x00 => ControllerMessage::BankSelectMSB(value),
+            0x01 => ControllerMessage::ModulationWheel(value),
+            0x02 => ControllerMessage::BreathControlMSB(value),
+            0x04 => ControllerMessage::FootControllerMSB(value),
+            0x05 => ControllerMessage::PortamentoTimeMSB(value),
+            0x06 => ControllerMessage::DataEntryMSB(value),
+            0x07 => ControllerMessage::ChannelVolumeMSB(value),
+            0x08 => ControllerMessage::BalanceMSB(value),
+            0x0A => ControllerMessage::PanMSB(value),
+            0x0B => ControllerMessage::ExpressionControllerMSB(value),
+            0x0C => ControllerMessage::EffectControl1MSB(value),
+            0x0D => ControllerMessage::EffectControl2MSB(value),
+            0x10 => ControllerMessage::GeneralPurposeController1MSB(value),
+            0x11 => ControllerMessage::GeneralPurposeController2MSB(value),
+            0x12 => ControllerMessage::GeneralPurposeController3MSB(value),
+            0x13 => ControllerMessage::GeneralPurposeController4MSB(value),
+            0x20 => ControllerMessage::BankSelectLSB(value),
+            0x21 => ControllerMessage::ModulationWheelLSB(value),
+            0x22 => ControllerMessage::BreathControlLSB(value),
+            0x24 => ControllerMessage::FootControllerLSB(value),
+            0x25 => ControllerMessage::PortamentoTimeLSB(value),
+            0x26 => ControllerMessage::DataEntryLSB(value),
+            0x27 => ControllerMessage::ChannelVolumeLSB(value),
+            0x28 => ControllerMessage::BalanceLSB(value),
+            0x2A => ControllerMessage::PanLSB(value),
+            0x2B => ControllerMessage::ExpressionControllerLSB(value),
+            0x2C => ControllerMessage::EffectControl1LSB(value),
+            0x2D => ControllerMessage::EffectControl2LSB(value),
+            0x30 => ControllerMessage::GeneralPurposeController1LSB(value),
+            0x31 => ControllerMessage::GeneralPurposeController2LSB(value),
+            0x32 => ControllerMessage::GeneralPurposeController3LSB(value),
+            0x33 => ControllerMessage::GeneralPurposeController4LSB(value),
+            0x40 => ControllerMessage::DamperPedalOn(value),
+            0x41 => ControllerMessage::PortamentoOnOff(value),
+            0x42 => ControllerMessage::SostenutoOnOff(value),
+            0x43 => ControllerMessage::SoftPedalOnOff(value),
+            0x44 => ControllerMessage::LegatoFootswitch(value),
+            0x45 => ControllerMessage::Hold2(value),
+            0x46 => ControllerMessage::SoundVariation(value),
+            0x47 => ControllerMessage::HarmonicIntensity(value),
+            0x48 => ControllerMessage::ReleaseTime(value),
+            0x49 => ControllerMessage::AttackTime(value),
+            0x4A => ControllerMessage::Brightness(value),
+            0x4B => ControllerMessage::DecayTime(value),
+            0x4C => ControllerMessage::VibratoRate(value),
+            0x4D => ControllerMessage::VibratoDepth(value),
+            0x4E => ControllerMessage::VibratoDelay(value),
+            0x50 => ControllerMessage::GeneralPurposeController5(value),
+            0x51 => ControllerMessage::GeneralPurposeController6(value),
+            0x52 => ControllerMessage::GeneralPurposeController7(value),
+            0x53 => ControllerMessage::GeneralPurposeController8(value),
+            0x54 => ControllerMessage::PortamentoControl(value),
+            0x58 => ControllerMessage::HighResolutionVelocityPrefix(value),
+            0x5B => ControllerMessage::EffectsDepth1(value),
+            0x5C => ControllerMessage::EffectsDepth2(value),
+            0x5D => ControllerMessage::EffectsDepth3(value),
+            0x5E => ControllerMessage::EffectsDepth4(value),
+            0x5F => ControllerMessage::EffectsDepth5(value),
+            0x60 => ControllerMessage::DataIncrement(value),
+            0x61 => ControllerMessage::DataDecrement(value),
+            0x62 => ControllerMessage::NonRegisteredParameterNumberLSB(value),
+            0x63 => ControllerMessage::NonRegisteredParameterNumberMSB(value),
+            0x64 => ControllerMessage::RegisteredParameterNumberLSB(value),
+            0x65 => ControllerMessage::RegisteredParameterNumberMSB(value),
+            0x78 => ControllerMessage::AllSoundOff(value),
+            0x79 => ControllerMessage::ResetAllControllers(value),
+            0x7A => ControllerMessage::LocalControl(value),
+            0x7B => ControllerMessage::AllNotesOff(value),
+            0x7C => ControllerMessage::OmniModeOff(value),
+            0x7D => ControllerMessage::OmniModeOn(value),
+            0x7E => ControllerMessage::PolyModeOnOffAllNotesOff(value),
+            0x7F => ControllerMessage::PolyModeOn(value),
+            number => ControllerMessage::Other { number, value }
+        }
+    }
+
+    // Controller number and data byte
+    pub fn to_raw(self) -> (u8, u8) {
+        match self {
+            ControllerMessage::BankSelectMSB(v) => (0x00, v),
+            ControllerMessage::ModulationWheel(v) => (0x01, v),
+            ControllerMessage::BreathControlMSB(v) => (0x02, v),
+            ControllerMessage::FootControllerMSB(v) => (0x04, v),
+            ControllerMessage::PortamentoTimeMSB(v) => (0x05, v),
+            ControllerMessage::DataEntryMSB(v) => (0x06, v),
+            ControllerMessage::ChannelVolumeMSB(v) => (0x07, v),
+            ControllerMessage::BalanceMSB(v) => (0x08, v),
+            ControllerMessage::PanMSB(v) => (0x0A, v),
+            ControllerMessage::ExpressionControllerMSB(v) => (0x0B, v),
+            ControllerMessage::EffectControl1MSB(v) => (0x0C, v),
+            ControllerMessage::EffectControl2MSB(v) => (0x0D, v),
+            ControllerMessage::GeneralPurposeController1MSB(v) => (0x10, v),
+            ControllerMessage::GeneralPurposeController2MSB(v) => (0x11, v),
+            ControllerMessage::GeneralPurposeController3MSB(v) => (0x12, v),
+            ControllerMessage::GeneralPurposeController4MSB(v) => (0x13, v),
+            ControllerMessage::BankSelectLSB(v) => (0x20, v),
+            ControllerMessage::ModulationWheelLSB(v) => (0x21, v),
+            ControllerMessage::BreathControlLSB(v) => (0x22, v),
+            ControllerMessage::FootControllerLSB(v) => (0x24, v),
+            ControllerMessage::PortamentoTimeLSB(v) => (0x25, v),
+            ControllerMessage::DataEntryLSB(v) => (0x26, v),
+            ControllerMessage::ChannelVolumeLSB(v) => (0x27, v),
+            ControllerMessage::BalanceLSB(v) => (0x28, v),
+            ControllerMessage::PanLSB(v) => (0x2A, v),
+            ControllerMessage::ExpressionControllerLSB(v) => (0x2B, v),
+            ControllerMessage::EffectControl1LSB(v) => (0x2C, v),
+            ControllerMessage::EffectControl2LSB(v) => (0x2D, v),
+            ControllerMessage::GeneralPurposeController1LSB(v) => (0x30, v),
+            ControllerMessage::GeneralPurposeController2LSB(v) => (0x31, v),
+            ControllerMessage::GeneralPurposeController3LSB(v) => (0x32, v),
+            ControllerMessage::GeneralPurposeController4LSB(v) => (0x33, v),
+            ControllerMessage::DamperPedalOn(v) => (0x40, v),
+            ControllerMessage::PortamentoOnOff(v) => (0x41, v),
+            ControllerMessage::SostenutoOnOff(v) => (0x42, v),
+            ControllerMessage::SoftPedalOnOff(v) => (0x43, v),
+            ControllerMessage::LegatoFootswitch(v) => (0x44, v),
+            ControllerMessage::Hold2(v) => (0x45, v),
+            ControllerMessage::SoundVariation(v) => (0x46, v),
+            ControllerMessage::HarmonicIntensity(v) => (0x47, v),
+            ControllerMessage::ReleaseTime(v) => (0x48, v),
+            ControllerMessage::AttackTime(v) => (0x49, v),
+            ControllerMessage::Brightness(v) => (0x4A, v),
+            ControllerMessage::DecayTime(v) => (0x4B, v),
+            ControllerMessage::VibratoRate(v) => (0x4C, v),
+            ControllerMessage::VibratoDepth(v) => (0x4D, v),
+            ControllerMessage::VibratoDelay(v) => (0x4E, v),
+            ControllerMessage::GeneralPurposeController5(v) => (0x50, v),
+            ControllerMessage::GeneralPurposeController6(v) => (0x51, v),
+            ControllerMessage::GeneralPurposeController7(v) => (0x52, v),
+            ControllerMessage::GeneralPurposeController8(v) => (0x53, v),
+            ControllerMessage::PortamentoControl(v) => (0x54, v),
+            ControllerMessage::HighResolutionVelocityPrefix(v) => (0x58, v),
+            ControllerMessage::EffectsDepth1(v) => (0x5B, v),
+            ControllerMessage::EffectsDepth2(v) => (0x5C, v),
+            ControllerMessage::EffectsDepth3(v) => (0x5D, v),
+            ControllerMessage::EffectsDepth4(v) => (0x5E, v),
+            ControllerMessage::EffectsDepth5(v) => (0x5F, v),
+            ControllerMessage::DataIncrement(v) => (0x60, v),
+            ControllerMessage::DataDecrement(v) => (0x61, v),
+            ControllerMessage::NonRegisteredParameterNumberLSB(v) => (0x62, v),
+            ControllerMessage::NonRegisteredParameterNumberMSB(v) => (0x63, v),
+            ControllerMessage::RegisteredParameterNumberLSB(v) => (0x64, v),
+            ControllerMessage::RegisteredParameterNumberMSB(v) => (0x65, v),
+            ControllerMessage::AllSoundOff(v) => (0x78, v),
+            ControllerMessage::ResetAllControllers(v) => (0x79, v),
+            ControllerMessage::LocalControl(v) => (0x7A, v),
+            ControllerMessage::AllNotesOff(v) => (0x7B, v),
+            ControllerMessage::OmniModeOff(v) => (0x7C, v),
+            ControllerMessage::OmniModeOn(v) => (0x7D, v),
+            ControllerMessage::PolyModeOnOffAllNotesOff(v) => (0x7E, v),
+            ControllerMessage::PolyModeOn(v) => (0x7F, v),
+            ControllerMessage::Other { number, value } => (number, value),
+        }
+    }
 }
 
-#[derive(Debug,Clone)]
+// Switches like the pedals (64-69) and Local Control are on from this value on. The raw value
+// is kept, because some devices treat the range below as a half pedal.
+pub const SWITCH_ON: u8 = 64;
+
+// Current value of every controller of a channel. Controllers 0-31 and 32-63 form MSB/LSB
+// pairs which can be read back as a single 14 bit value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerValues {
+    values: [u8; 128]
+}
+
+impl ControllerValues {
+    pub fn new() -> Self {
+        Self { values: [0; 128] }
+    }
+
+    pub fn update(&mut self, message: &ControllerMessage) {
+        let (number, value) = message.to_raw();
+        self.values[number as usize & 0x7F] = value;
+        if number < 32 {
+            // Receiving an MSB resets the LSB as demanded by the midi specification
+            self.values[number as usize + 32] = 0;
+        }
+    }
+
+    pub fn get(&self, number: u8) -> u8 {
+        self.values[number as usize & 0x7F]
+    }
+
+    pub fn is_on(&self, number: u8) -> bool {
+        self.get(number) >= SWITCH_ON
+    }
+
+    // Combines the MSB controller `msb_number` (0-31) with its LSB controller
+    pub fn get_14bit(&self, msb_number: u8) -> u16 {
+        let msb = self.values[msb_number as usize & 0x1F] as u16;
+        let lsb = self.values[(msb_number as usize & 0x1F) + 32] as u16;
+        (msb << 7) | lsb
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
 pub enum MidiEvent {
     ControlChange(ControllerMessage),
     ProgramChange(u8),
     PitchWheelChange(u32),
     NoteOn { key: u8, velocity: u8 },
-    NoteOff { key: u8, velocity: u8 }
+    NoteOff { key: u8, velocity: u8 },
+    PolyphonicKeyPressure { key: u8, pressure: u8 },
+    ChannelPressure(u8)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HeaderChunk {
     pub format: Format,
     pub ntrks: u32,
     pub division: Division
 }
 
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,PartialEq,Eq)]
 pub struct TrackChunk { 
     pub events: Vec<(u32, Event)>
 }
 
-#[derive(Debug, Clone)]
-pub enum Chunk {
-    Header(HeaderChunk),
-    Track(TrackChunk),
-    Unknown
-}
-
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,PartialEq,Eq)]
 pub enum Event {
     Midi(u8,MidiEvent),
-    Sysex,
+    Sysex(Vec<u8>), // Message without the leading 0xF0 and the terminating 0xF7
+    Escape(Vec<u8>), // Arbitrary bytes sent via an 0xF7 event
     Meta(MetaEvent)
 }
 
+// Universal and manufacturer specific system exclusive messages the synthesizer reacts to
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SysexMessage {
+    GmSystemOn,
+    GmSystemOff,
+    Gm2SystemOn,
+    GsReset,
+    XgSystemOn,
+    MasterVolume(u16),
+    Unknown
+}
+
+impl SysexMessage {
+    pub fn parse(data: &[u8]) -> Self {
+        match data {
+            [0x7E, _, 0x09, 0x01, ..] => SysexMessage::GmSystemOn,
+            [0x7E, _, 0x09, 0x02, ..] => SysexMessage::GmSystemOff,
+            [0x7E, _, 0x09, 0x03, ..] => SysexMessage::Gm2SystemOn,
+            [0x7F, _, 0x04, 0x01, lsb, msb, ..] => SysexMessage::MasterVolume(((*msb as u16) << 7) | *lsb as u16),
+            [0x41, _, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, ..] => SysexMessage::GsReset,
+            [0x43, device, 0x4C, 0x00, 0x00, 0x7E, 0x00, ..] if device >> 4 == 1 => SysexMessage::XgSystemOn,
+            _ => SysexMessage::Unknown
+        }
+    }
+}
+
 #[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub enum MidiErrorType {
     IO,
-    InvalidMidi
+    InvalidMidi,
+    UnexpectedEof,
+    TruncatedChunk,
+    MissingHeader,
+    InvalidHeader,
+    UnknownFormat,
+    UnsupportedDivision,
+    InvalidVlq,
+    InvalidRunningStatus,
+    UnexpectedStatus,
+    InvalidMetaLength,
+    TrackCountMismatch
 }
 
-#[derive(Debug, Clone)]
+// The position fields are filled in while the error travels up from the place it occurred.
+// Values that are already set are kept, as they were recorded closer to the actual problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MidiError {
     pub message: String,
-    pub error_type: MidiErrorType
+    pub error_type: MidiErrorType,
+    pub offset: Option<u64>, // Byte offset from the start of the input
+    pub chunk: Option<usize>,
+    pub track: Option<usize>,
+    pub event: Option<usize>, // Index of the event inside its track chunk
+}
+
+impl MidiError {
+    pub fn new(error_type: MidiErrorType, message: impl Into<String>) -> Self {
+        Self { message: message.into(), error_type, offset: None, chunk: None, track: None, event: None }
+    }
+
+    pub fn at_offset(mut self, offset: u64) -> Self {
+        self.offset.get_or_insert(offset);
+        self
+    }
+
+    pub fn in_chunk(mut self, chunk: usize) -> Self {
+        self.chunk.get_or_insert(chunk);
+        self
+    }
+
+    pub fn in_track(mut self, track: usize) -> Self {
+        self.track.get_or_insert(track);
+        self
+    }
+
+    pub fn at_event(mut self, event: usize) -> Self {
+        self.event.get_or_insert(event);
+        self
+    }
 }
 
 impl Display for MidiError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(fmt, "{:?}Error: {}", self.error_type, self.message)
+        write!(fmt, "{:?}Error: {}", self.error_type, self.message)?;
+        let mut position = vec![];
+        if let Some(offset) = self.offset {
+            position.push(format!("byte {}", offset));
+        }
+        if let Some(chunk) = self.chunk {
+            position.push(format!("chunk {}", chunk));
+        }
+        if let Some(track) = self.track {
+            position.push(format!("track {}", track));
+        }
+        if let Some(event) = self.event {
+            position.push(format!("event {}", event));
+        }
+        if !position.is_empty() {
+            write!(fmt, " (at {})", position.join(", "))?;
+        }
+        Ok(())
     }
 }
 
 impl std::error::Error for MidiError {}
 
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ParseMode {
+    Strict, // Every violation of the file structure is an error
+    Lenient // Damaged files are repaired where possible and the repairs are reported
+}
 
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct ParseOptions {
+    pub mode: ParseMode,
+    pub text_encoding: TextEncoding // Fallback for texts that are not valid UTF-8
+}
 
-fn read_bytes(reader: &mut impl Read, buf: &mut [u8]) -> Result<(),MidiError> {
-    match reader.read(buf) {
-        Ok(length) => {
-            if length == 0 {
-                Err(MidiError { message: String::from("Unexpectedly reached end of file"), error_type: MidiErrorType::InvalidMidi })
-            } else {
-                Ok(())
-            }
-        },
-        Err(err) => {
-            Err(MidiError { message: err.to_string(), error_type: MidiErrorType::IO })
+impl ParseOptions {
+    pub fn strict() -> Self {
+        Self { mode: ParseMode::Strict, text_encoding: TextEncoding::Auto }
+    }
+
+    pub fn lenient() -> Self {
+        Self { mode: ParseMode::Lenient, text_encoding: TextEncoding::Auto }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum DiagnosticKind {
+    JunkBeforeHeader,
+    UnknownChunk,
+    WrongChunkLength,
+    InvalidEvent,
+    MissingEndOfTrack,
+    DataAfterEndOfTrack,
+    UnterminatedSysex,
+    TrackCountMismatch,
+    JunkBetweenChunks,
+    TrailingData
+}
+
+// A problem that was tolerated or repaired while parsing
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+    pub offset: Option<u64>, // None for problems of the file as a whole
+    pub track: Option<usize>
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{:?}: {}", self.kind, self.message)?;
+        let mut location = Vec::new();
+        if let Some(offset) = self.offset {
+            location.push(format!("at byte {}", offset));
+        }
+        if let Some(track) = self.track {
+            location.push(format!("track {}", track));
         }
+        if !location.is_empty() {
+            write!(fmt, " ({})", location.join(", "))?;
+        }
+        Ok(())
     }
 }
 
-// Read a variable length quantity as used in midi files
+// Fill the whole buffer. Readers are allowed to return less bytes than requested, so a single
+// call to read is not enough.
+fn read_bytes(reader: &mut impl Read, buf: &mut [u8]) -> Result<(),MidiError> {
+    reader.read_exact(buf).map_err(|err| {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            MidiError::new(MidiErrorType::UnexpectedEof, "Unexpectedly reached end of file")
+        } else {
+            MidiError::new(MidiErrorType::IO, err.to_string())
+        }
+    })
+}
+
+// Read a variable length quantity as used in midi files. They are limited to four bytes.
 fn read_vlq(reader: &mut impl Read) -> Result<u32,MidiError> {
     let mut out = 0;
     let mut buffer = [0];
-    
-    loop {
+
+    for _ in 0..4 {
         read_bytes(reader, &mut buffer)?;
-        out <<= 7;
+        out = (out << 7) | (buffer[0] & 0x7F) as u32;
         if buffer[0] & (1<<7) == 0 {
-            out |= buffer[0] as u32;
-            break;
-        } else {
-            buffer[0] ^= 1 << 7;
-            out |= buffer[0] as u32;
+            return Ok(out);
         }
     }
-    return Ok(out);
+    Err(MidiError::new(MidiErrorType::InvalidVlq, "Variable length quantity is longer than four bytes"))
 }
 
 fn read_midi_event(reader: &mut impl Read, midi_event_type: u8, first_byte: u8) -> Result<MidiEvent,MidiError> {
@@ -150,32 +551,7 @@ fn read_midi_event(reader: &mut impl Read, midi_event_type: u8, first_byte: u8)
         0b1011 => { // Control Change
             let mut control_change_data = [0];
             read_bytes(reader,&mut control_change_data)?;
-            MidiEvent::ControlChange(match first_byte {
-                0x79 => ControllerMessage::ResetAllControllers,
-                0x00 => ControllerMessage::BankSelectMSB(control_change_data[0]),
-                0x20 => ControllerMessage::BankSelectLSB(control_change_data[0]),
-                0x07 => ControllerMessage::ChannelVolumeMSB(control_change_data[0]),
-                0x0A => ControllerMessage::PanMSB(control_change_data[0]),
-                0x0B => ControllerMessage::ExpressionControllerMSB(control_change_data[0]),
-                0x5B => ControllerMessage::EffectsDepth1LSB(control_change_data[0]),
-                0x5D => ControllerMessage::EffectsDepth3LSB(control_change_data[0]),
-                0x63 => ControllerMessage::NonRegisteredParameterNumberMSB(control_change_data[0]),
-                0x62 => ControllerMessage::NonRegisteredParameterNumberLSB(control_change_data[0]),
-                0x65 => ControllerMessage::RegisteredParameterNumberMSB(control_change_data[0]),
-                0x64 => ControllerMessage::RegisteredParameterNumberLSB(control_change_data[0]),
-                0x06 => ControllerMessage::DataEntryMSB(control_change_data[0]),
-                0x40 => ControllerMessage::DamperPedalOn(control_change_data[0] >= 64),
-                0x78 => ControllerMessage::AllSoundOff,
-                0x7B => ControllerMessage::AllNotesOff,
-                0x01 => ControllerMessage::ModulationWheel(control_change_data[0]),
-                0x02 => ControllerMessage::BreathControlMSB(control_change_data[0]),
-                0x41 => ControllerMessage::PortamentoOnOff(control_change_data[0] >= 64),
-                0x05 => ControllerMessage::PortamentoTimeMSB(control_change_data[0]),
-                0x7E => ControllerMessage::PolyModeOnOffAllNotesOff,
-                0x12 => ControllerMessage::GeneralPurposeController1MSB(control_change_data[0]),
-                0x26 => ControllerMessage::DataEntryLSB(control_change_data[0]),
-                _ => todo!("{}", first_byte)
-            })
+            MidiEvent::ControlChange(ControllerMessage::from_raw(first_byte, control_change_data[0]))
         },
         0b1100 => { // Program Change
             MidiEvent::ProgramChange(first_byte)
@@ -196,16 +572,37 @@ fn read_midi_event(reader: &mut impl Read, midi_event_type: u8, first_byte: u8)
             read_bytes(reader,&mut note_data)?;
             MidiEvent::NoteOff { key: first_byte, velocity: note_data[0] }
         }
-        _ => todo!("Midi type: {:b}", midi_event_type)
+        0b1010 => { // Polyphonic Key Pressure (Aftertouch)
+            let mut pressure_data = [0];
+            read_bytes(reader,&mut pressure_data)?;
+            MidiEvent::PolyphonicKeyPressure { key: first_byte, pressure: pressure_data[0] }
+        }
+        0b1101 => { // Channel Pressure (Aftertouch)
+            MidiEvent::ChannelPressure(first_byte)
+        }
+        _ => return Err(MidiError::new(MidiErrorType::InvalidMidi, format!("Unknown midi event type {:X}", midi_event_type)))
     })
 }
 
-fn get_utf8(data: &Vec<u8>) -> Result<String,MidiError> {
-    let name_str = str::from_utf8(data).map_err(|x| MidiError { message: x.to_string(), error_type: MidiErrorType::InvalidMidi })?;
-    Ok(name_str.to_string())
+// The length comes from the file, so it is checked before anything is allocated
+fn read_data(reader: &mut &[u8], length: u32) -> Result<Vec<u8>,MidiError> {
+    if length as usize > reader.len() {
+        return Err(MidiError::new(MidiErrorType::TruncatedChunk, format!("{} bytes of data were announced but only {} are left", length, reader.len())));
+    }
+    let mut data = vec![0; length as usize];
+    read_bytes(reader, &mut data)?;
+    Ok(data)
 }
 
-fn read_event(reader: &mut impl Read, last_status: &mut u8) -> Result<(u32, Event), MidiError> {
+fn check_meta_length(name: &str, data: &[u8], expected: usize) -> Result<(),MidiError> {
+    if data.len() == expected {
+        Ok(())
+    } else {
+        Err(MidiError::new(MidiErrorType::InvalidMetaLength, format!("{} meta event has a length of {} instead of {}", name, data.len(), expected)))
+    }
+}
+
+fn read_event(reader: &mut &[u8], last_status: &mut u8, text_encoding: TextEncoding) -> Result<(u32, Event), MidiError> {
     let dt = read_vlq(reader)?;
     Ok((dt, {
         let mut data = [0];
@@ -215,62 +612,87 @@ fn read_event(reader: &mut impl Read, last_status: &mut u8) -> Result<(u32, Even
             read_bytes(reader, &mut data)?;
             let meta_type = data[0];
             let length = read_vlq(reader)?; // This should always work though the documentation is unclear
+            let meta_data = read_data(reader, length)?;
             Event::Meta(match meta_type {
-                0x03 => { // Sequence/Track Name
-                    let mut name_data = vec![0; length as usize];
-                    read_bytes(reader, &mut name_data)?;
-                    let name_str = get_utf8(&name_data)?;
-                    MetaEvent::SequenceTrackName { text: name_str }
-
-                }
+                0x00 => { // Sequence Number
+                    let number = match meta_data.len() {
+                        0 => None,
+                        2 => Some(u16::from_be_bytes([meta_data[0], meta_data[1]])),
+                        x => return Err(MidiError::new(MidiErrorType::InvalidMetaLength, format!("Sequence Number meta event has a length of {} instead of 0 or 2", x)))
+                    };
+                    MetaEvent::SequenceNumber { number }
+                },
+                0x01 => MetaEvent::Text { text: MetaText::decode(meta_data, text_encoding) },
+                0x02 => MetaEvent::CopyrightNotice { text: MetaText::decode(meta_data, text_encoding) },
+                0x03 => MetaEvent::SequenceTrackName { text: MetaText::decode(meta_data, text_encoding) },
+                0x04 => MetaEvent::InstrumentName { text: MetaText::decode(meta_data, text_encoding) },
+                0x05 => MetaEvent::Lyric { text: MetaText::decode(meta_data, text_encoding) },
+                0x06 => MetaEvent::Marker { text: MetaText::decode(meta_data, text_encoding) },
+                0x07 => MetaEvent::CuePoint { text: MetaText::decode(meta_data, text_encoding) },
+                0x08 => MetaEvent::ProgramName { text: MetaText::decode(meta_data, text_encoding) },
+                0x09 => MetaEvent::DeviceName { text: MetaText::decode(meta_data, text_encoding) },
+                0x20 => { // MIDI Channel Prefix
+                    check_meta_length("MIDI Channel Prefix", &meta_data, 1)?;
+                    MetaEvent::MidiChannelPrefix { channel: meta_data[0] }
+                },
+                0x21 => { // MIDI Port
+                    check_meta_length("MIDI Port", &meta_data, 1)?;
+                    MetaEvent::MidiPort { port: meta_data[0] }
+                },
+                0x2F => {
+                    MetaEvent::EndOfTrack
+                },
                 0x51 => { // Set Tempo
-                    assert!(length == 3);
-                    let mut tempo_data = vec![0;3];
-                    read_bytes(reader, &mut tempo_data)?;
-                    tempo_data.insert(0,0);
-                    let tempo = u32::from_be_bytes(tempo_data.try_into().unwrap());
+                    check_meta_length("Set Tempo", &meta_data, 3)?;
+                    let tempo = u32::from_be_bytes([0, meta_data[0], meta_data[1], meta_data[2]]);
                     MetaEvent::SetTempo { tempo }
                 },
+                0x54 => { // SMPTE Offset
+                    check_meta_length("SMPTE Offset", &meta_data, 5)?;
+                    let fps = match meta_data[0] >> 5 & 0b11 {
+                        0 => 24,
+                        1 => 25,
+                        2 => 29, // 30 drop frame
+                        _ => 30
+                    };
+                    let hours = meta_data[0] & 0b1_1111;
+                    MetaEvent::SmpteOffset { fps, hours, minutes: meta_data[1], seconds: meta_data[2], frames: meta_data[3], fractional_frames: meta_data[4] }
+                },
                 0x58 => { // Time Signature
-                    assert!(length == 4);
-                    let mut time_sig_data = [0;4];
-                    read_bytes(reader, &mut time_sig_data)?;
-                    let denominator = time_sig_data[0];
-                    let numerator = 2_u8.pow(time_sig_data[1] as u32);
-                    let metronome_clocks = time_sig_data[2];
-                    let notated_32s_per_quarter = time_sig_data[3];
-                    MetaEvent::TimeSignature { denominator, numerator, metronome_clocks, notated_32s_per_quarter }
-                }
-                0x01 => { // Text
-                    let mut text_data = vec![0; length as usize];
-                    read_bytes(reader, &mut text_data[..])?;
-                    let text_str = get_utf8(&text_data)?;
-                    MetaEvent::Text { text: text_str }
+                    check_meta_length("Time Signature", &meta_data, 4)?;
+                    let numerator = meta_data[0];
+                    let denominator = 2_u8.checked_pow(meta_data[1] as u32).ok_or_else(||
+                        MidiError::new(MidiErrorType::InvalidMidi, format!("Time signature denominator 2^{} is too large", meta_data[1]))
+                    )?;
+                    let metronome_clocks = meta_data[2];
+                    let notated_32s_per_quarter = meta_data[3];
+                    MetaEvent::TimeSignature { numerator, denominator, metronome_clocks, notated_32s_per_quarter }
+                },
+                0x59 => { // Key Signature
+                    check_meta_length("Key Signature", &meta_data, 2)?;
+                    MetaEvent::KeySignature { sharps_flats: meta_data[0] as i8, minor: meta_data[1] == 1 }
+                },
+                0x7F => {
+                    MetaEvent::SequencerSpecific { data: meta_data }
                 },
-                0x2F => {
-                    MetaEvent::EndOfTrack
-                }
                 _ => { // Unknown/Not implemented
-                    let mut unknown_data = vec![0; length as usize];
-                    read_bytes(reader, &mut unknown_data)?;
-                    println!("Unknown Meta Event: {:X}", meta_type);
-                    MetaEvent::Unknown
+                    MetaEvent::Unknown { meta_type, data: meta_data }
                 }
             })
 
         } else if signal >> 4 == 0xF { // Sysex
+            // Both packet types store their length in front of the data. Whether an unterminated
+            // 0xF0 packet is continued by the following 0xF7 packets is decided in read_chunk.
             match signal {
                 0xF0 => {
-                    let mut ignored_data = [0];
-                    loop {
-                        read_bytes(reader, &mut ignored_data)?;
-                        if ignored_data[0] == 0b11110111 {
-                            break;
-                        }
-                    }
-                    Event::Sysex
+                    let length = read_vlq(reader)?;
+                    Event::Sysex(read_data(reader, length)?)
+                }
+                0xF7 => {
+                    let length = read_vlq(reader)?;
+                    Event::Escape(read_data(reader, length)?)
                 }
-                _ => todo!()
+                _ => return Err(MidiError::new(MidiErrorType::UnexpectedStatus, format!("System message {:X} is not allowed in a track", signal)))
             }
         } else if signal & 0b1000_0000 != 0 { // Midi
             let midi_event_type = signal >> 4;
@@ -280,6 +702,9 @@ fn read_event(reader: &mut impl Read, last_status: &mut u8) -> Result<(u32, Even
             *last_status = signal;
             Event::Midi(channel, read_midi_event(reader, midi_event_type, first_byte_data[0])?)
         } else { // Signal Byte was omitted
+            if *last_status == 0 {
+                return Err(MidiError::new(MidiErrorType::InvalidRunningStatus, format!("Data byte {:X} is not preceded by a status byte", signal)));
+            }
             let midi_event_type = *last_status >> 4;
             let channel = *last_status & 0xF;
             Event::Midi(channel, read_midi_event(reader, midi_event_type, signal)?)
@@ -287,105 +712,454 @@ fn read_event(reader: &mut impl Read, last_status: &mut u8) -> Result<(u32, Even
     }))
 }
 
-fn read_chunk(reader: &mut impl Read) -> Result<Chunk, MidiError> {
-    let mut name_bytes = [0_u8;4];
-    read_bytes(reader,&mut name_bytes)?;
+// Result of reading the events of a track chunk. Reading stops at the End of Track event or
+// at the first error, in which case the events read so far are kept.
+struct TrackParse {
+    events: Vec<(u32, Event)>,
+    error: Option<MidiError>,
+    unread: usize, // Bytes following the End of Track event
+    unterminated_sysex: Vec<u64> // Offsets of sysex events that other events interrupted before their F7
+}
 
-    let mut name = None;
-    if let Ok(s) = str::from_utf8(&name_bytes) {
-        name = Some(String::from(s));
-    };
+fn read_track(content: &[u8], data_offset: u64, text_encoding: TextEncoding) -> TrackParse {
+    let mut track_reader = content;
+    let mut events = vec![];
+    let mut stored_signal = 0;
+    let mut open_sysex = None; // Index and offset of a sysex event that awaits continuation packets
+    let mut unterminated_sysex = vec![];
+    let mut carried_dt = 0; // Delta times of continuation packets are added to the next event
+    for event_index in 0.. {
+        let event_offset = data_offset + (content.len() - track_reader.len()) as u64;
+        let (dt, event) = match read_event(&mut track_reader, &mut stored_signal, text_encoding) {
+            Ok(event) => event,
+            Err(e) => {
+                let e = if e.error_type == MidiErrorType::UnexpectedEof {
+                    MidiError::new(MidiErrorType::TruncatedChunk, "Track data ended before the End of Track event")
+                } else {
+                    e
+                };
+                return TrackParse { events, error: Some(e.at_event(event_index).at_offset(event_offset)), unread: 0, unterminated_sysex };
+            }
+        };
+        let dt = dt + carried_dt;
+        carried_dt = 0;
+        // Only F7 packets continue a sysex event, any other event closes it as it is
+        if let (Some((_, offset)), false) = (open_sysex, matches!(event, Event::Escape(_))) {
+            unterminated_sysex.push(offset);
+            open_sysex = None;
+        }
+        match (event, open_sysex) {
+            (Event::Sysex(mut data), _) => {
+                let terminated = data.last() == Some(&0xF7);
+                if terminated {
+                    data.pop();
+                }
+                events.push((dt, Event::Sysex(data)));
+                open_sysex = if terminated { None } else { Some((events.len() - 1, event_offset)) };
+            },
+            (Event::Escape(mut data), Some((index, _))) => {
+                if data.last() == Some(&0xF7) {
+                    data.pop();
+                    open_sysex = None;
+                }
+                if let (_, Event::Sysex(sysex_data)) = &mut events[index] {
+                    sysex_data.append(&mut data);
+                }
+                carried_dt = dt;
+            },
+            (Event::Meta(MetaEvent::EndOfTrack), _) => {
+                events.push((dt, Event::Meta(MetaEvent::EndOfTrack)));
+                break;
+            },
+            (event, _) => {
+                events.push((dt, event));
+            }
+        }
+    }
+    TrackParse { events, error: None, unread: track_reader.len(), unterminated_sysex }
+}
 
+fn read_header(input: &[u8]) -> Result<HeaderChunk, MidiError> {
+    let mut reader = input;
+    let mut name_bytes = [0_u8;4];
+    read_bytes(&mut reader, &mut name_bytes)?;
     let mut length_bytes = [0_u8;4];
-    read_bytes(reader, &mut length_bytes)?;
+    read_bytes(&mut reader, &mut length_bytes)?;
     let length = u32::from_be_bytes(length_bytes);
-    
-    Ok(match name {
-        Some(s) if s == "MThd" => {
-            assert!(length == 6);
-            let mut data = [0_u8;2];
-            read_bytes(reader,&mut data)?;
-            let format = match u16::from_be_bytes(data.clone()) {
-                0 => Ok(Format::SingleTrack),
-                1 => Ok(Format::SimulTrack),
-                2 => Ok(Format::SequenceTrack),
-                x => {
-                    Err(MidiError { message: format!("Unknown midi format {}", x), error_type: MidiErrorType::InvalidMidi })
+    if length < 6 {
+        return Err(MidiError::new(MidiErrorType::InvalidHeader, format!("Header chunk has a length of {} instead of 6", length)));
+    }
+    let truncated = |e: MidiError| {
+        if e.error_type == MidiErrorType::UnexpectedEof {
+            MidiError::new(MidiErrorType::TruncatedChunk, format!("Chunk is shorter than its declared length of {} bytes", length))
+        } else {
+            e
+        }
+    };
+
+    let mut data = [0_u8;2];
+    read_bytes(&mut reader,&mut data).map_err(truncated)?;
+    let format = match u16::from_be_bytes(data) {
+        0 => Ok(Format::SingleTrack),
+        1 => Ok(Format::SimulTrack),
+        2 => Ok(Format::SequenceTrack),
+        x => {
+            Err(MidiError::new(MidiErrorType::UnknownFormat, format!("Unknown midi format {}", x)))
+        }
+    }?;
+
+    read_bytes(&mut reader,&mut data).map_err(truncated)?;
+    let ntrks = u16::from_be_bytes(data) as u32;
+    read_bytes(&mut reader,&mut data).map_err(truncated)?;
+    let division = if data[0] >> 7 == 0 {
+        let ticks = u16::from_be_bytes(data) as u32;
+        if ticks == 0 {
+            return Err(MidiError::new(MidiErrorType::UnsupportedDivision, "Division of zero ticks per quarter note"));
+        }
+        Division::TicksPerQuarter(ticks)
+    } else {
+        let frame_rate = -(i8::from_be_bytes([data[0]]) as i32);
+        let ticks_per_frame = data[1];
+        if ![24, 25, 29, 30].contains(&frame_rate) || ticks_per_frame == 0 {
+            return Err(MidiError::new(MidiErrorType::UnsupportedDivision, format!("Unsupported SMPTE division of {} frames per second and {} ticks per frame", frame_rate, ticks_per_frame)));
+        }
+        Division::TicksPerFrame(frame_rate as u32, ticks_per_frame as u32)
+    };
+
+    // Future versions of the format may append fields to the header
+    read_data(&mut reader, length - 6).map_err(truncated)?;
+
+    Ok(HeaderChunk { format, ntrks, division })
+}
+
+fn is_chunk_name(bytes: &[u8]) -> bool {
+    bytes.len() >= 4 && bytes[..4].iter().all(|b| b.is_ascii_alphanumeric())
+}
+
+fn find_bytes(input: &[u8], start: usize, pattern: &[u8]) -> Option<usize> {
+    input.get(start..)?.windows(pattern.len()).position(|w| w == pattern).map(|p| p + start)
+}
+
+fn parse_smf(input: &[u8], options: &ParseOptions) -> Result<(MidiFile, Vec<Diagnostic>), MidiError> {
+    let lenient = options.mode == ParseMode::Lenient;
+    let mut diagnostics = vec![];
+    let diagnostic = |kind, message: String, offset: usize, track| Diagnostic { kind, message, offset: Some(offset as u64), track };
+
+    let mut position = 0;
+    if !input.starts_with(b"MThd") {
+        match find_bytes(input, 0, b"MThd") {
+            Some(start) if lenient => {
+                diagnostics.push(diagnostic(DiagnosticKind::JunkBeforeHeader, format!("Skipped {} bytes in front of the header chunk", start), 0, None));
+                position = start;
+            },
+            _ => return Err(MidiError::new(MidiErrorType::MissingHeader, "A midi file has to start with a header chunk").in_chunk(0).at_offset(0))
+        }
+    }
+    let mut header = read_header(&input[position..]).map_err(|e| e.in_chunk(0).at_offset(position as u64))?;
+    position += 8 + u32::from_be_bytes(input[position+4..position+8].try_into().unwrap()) as usize;
+
+    let mut tracks = vec![];
+    let mut chunk_index = 1;
+    while position < input.len() {
+        if !lenient && tracks.len() == header.ntrks as usize {
+            diagnostics.push(diagnostic(DiagnosticKind::TrailingData, format!("Ignored {} bytes after the last track", input.len() - position), position, None));
+            break;
+        }
+
+        let chunk_offset = position;
+        let remaining = &input[position..];
+        if remaining.len() < 8 || !is_chunk_name(remaining) {
+            if !lenient {
+                return Err(MidiError::new(MidiErrorType::InvalidMidi, "Expected the start of a chunk").in_chunk(chunk_index).at_offset(chunk_offset as u64));
+            }
+            match find_bytes(input, position + 1, b"MTrk") {
+                Some(next) => {
+                    diagnostics.push(diagnostic(DiagnosticKind::JunkBetweenChunks, format!("Skipped {} bytes of junk between chunks", next - position), position, None));
+                    position = next;
+                    continue;
+                },
+                None => {
+                    diagnostics.push(diagnostic(DiagnosticKind::TrailingData, format!("Ignored {} bytes after the last track", input.len() - position), position, None));
+                    break;
                 }
-            }?;
-
-            read_bytes(reader,&mut data)?;
-            let ntrks = u16::from_be_bytes(data.clone()) as u32;
-            read_bytes(reader,&mut data)?;
-            let division = if data[0] >> 7 == 0 {
-                Division::TicksPerQuarter(u16::from_be_bytes(data) as u32)
-            } else {
-                let frame_rate = i8::from_be_bytes([data[0]]);
-                let ticks_per_frame = data[1];
-                Division::TicksPerFrame((-frame_rate) as u32, ticks_per_frame as u32)
-            };
-
-            Chunk::Header(HeaderChunk { format, ntrks, division })
-        },
-        Some(s) if s == "MTrk"=> {
-            let mut content = vec![0;length as usize];
-            reader.read(&mut content).unwrap();
-            let mut track_reader = content.as_slice();
-            let mut events = vec![];
-            let mut stored_signal = 0;
-            loop {
-                let event = read_event(&mut track_reader, &mut stored_signal)?;
-                if let (_,Event::Meta(MetaEvent::EndOfTrack)) = event {
-                    events.push(event);
+            }
+        }
+
+        let length = u32::from_be_bytes(remaining[4..8].try_into().unwrap()) as usize;
+        let data_start = position + 8;
+        let declared_end = data_start + length;
+        let declared_fits = declared_end <= input.len();
+        if &remaining[..4] == b"MTrk" {
+            let track_index = tracks.len();
+            let mut end = declared_end;
+            let mut parsed = if declared_fits { Some(read_track(&input[data_start..declared_end], data_start as u64, options.text_encoding)) } else { None };
+            if !matches!(parsed, Some(TrackParse { error: None, .. })) {
+                if !lenient {
+                    let error = match parsed {
+                        Some(TrackParse { error: Some(error), .. }) => error,
+                        _ => MidiError::new(MidiErrorType::TruncatedChunk, format!("Chunk is shorter than its declared length of {} bytes", length))
+                    };
+                    return Err(error.in_track(track_index).in_chunk(chunk_index).at_offset(chunk_offset as u64));
+                }
+                // Assume that the track data reaches up to the next track chunk
+                end = find_bytes(input, data_start, b"MTrk").unwrap_or(input.len());
+                if end != declared_end {
+                    diagnostics.push(diagnostic(DiagnosticKind::WrongChunkLength, format!("Track chunk declares {} bytes but spans {} bytes", length, end - data_start), chunk_offset, Some(track_index)));
+                    parsed = Some(read_track(&input[data_start..end], data_start as u64, options.text_encoding));
+                }
+            }
+
+            let TrackParse { mut events, error, mut unread, unterminated_sysex } = parsed.unwrap();
+            for offset in unterminated_sysex {
+                diagnostics.push(diagnostic(DiagnosticKind::UnterminatedSysex, String::from("Closed a sysex event that was interrupted before its F7 byte"), offset as usize, Some(track_index)));
+            }
+            if let Some(error) = error {
+                let kind = if error.error_type == MidiErrorType::TruncatedChunk { DiagnosticKind::MissingEndOfTrack } else { DiagnosticKind::InvalidEvent };
+                diagnostics.push(diagnostic(kind, format!("Inserted End of Track event: {}", error.message), error.offset.unwrap_or(data_start as u64) as usize, Some(track_index)));
+                events.push((0, Event::Meta(MetaEvent::EndOfTrack)));
+            }
+            if lenient && unread > 0 {
+                // The declared length may reach into the next track chunk
+                let track_end = end - unread;
+                if let Some(next) = find_bytes(&input[..end], track_end, b"MTrk") {
+                    diagnostics.push(diagnostic(DiagnosticKind::WrongChunkLength, format!("Track chunk declares {} bytes but spans {} bytes", length, next - data_start), chunk_offset, Some(track_index)));
+                    unread = next - track_end;
+                    end = next;
+                }
+            }
+            if unread > 0 {
+                diagnostics.push(diagnostic(DiagnosticKind::DataAfterEndOfTrack, format!("Ignored {} bytes after the End of Track event", unread), end - unread, Some(track_index)));
+            }
+            tracks.push(TrackChunk { events });
+            position = end;
+        } else if declared_fits {
+            diagnostics.push(diagnostic(DiagnosticKind::UnknownChunk, format!("Skipped unknown chunk {}", String::from_utf8_lossy(&remaining[..4])), chunk_offset, None));
+            position = declared_end;
+        } else if lenient {
+            match find_bytes(input, data_start, b"MTrk") {
+                Some(next) => {
+                    diagnostics.push(diagnostic(DiagnosticKind::WrongChunkLength, format!("Unknown chunk {} declares {} bytes but the file ends before", String::from_utf8_lossy(&remaining[..4]), length), chunk_offset, None));
+                    position = next;
+                },
+                None => {
+                    diagnostics.push(diagnostic(DiagnosticKind::TrailingData, format!("Ignored {} bytes after the last track", input.len() - position), position, None));
                     break;
-                } else {
-                    events.push(event);
                 }
-                
-                
             }
-            Chunk::Track(TrackChunk { events } )
-        },
-        Some(_) | None => {
-            println!("WARNING: Unkown chunk type");
-            let mut content = vec![0;length as usize];
-            reader.read(&mut content).unwrap();
-            Chunk::Unknown
+        } else {
+            return Err(MidiError::new(MidiErrorType::TruncatedChunk, format!("Chunk is shorter than its declared length of {} bytes", length)).in_chunk(chunk_index).at_offset(chunk_offset as u64));
         }
-    })
+        chunk_index += 1;
+    }
+
+    if tracks.len() != header.ntrks as usize {
+        if !lenient {
+            return Err(MidiError::new(MidiErrorType::TrackCountMismatch, format!("Expected {} track chunks but found {}", header.ntrks, tracks.len())).in_chunk(chunk_index).at_offset(input.len() as u64));
+        }
+        diagnostics.push(Diagnostic { kind: DiagnosticKind::TrackCountMismatch, message: format!("Header announces {} tracks but {} were found", header.ntrks, tracks.len()), offset: None, track: None });
+        header.ntrks = tracks.len() as u32;
+    }
+
+    Ok((MidiFile { header, tracks, riff_info: None }, diagnostics))
 }
 
-#[derive(Clone)]
+fn parse_midi(input: &[u8], options: &ParseOptions) -> Result<(MidiFile, Vec<Diagnostic>), MidiError> {
+    if !is_riff(input) {
+        return parse_smf(input, options);
+    }
+
+    // Positions inside the embedded file are reported relative to the whole input
+    let mut diagnostics = vec![];
+    let (data, data_offset, info) = unwrap_rmid(input, options.mode == ParseMode::Lenient, &mut diagnostics)?;
+    let (mut file, smf_diagnostics) = parse_smf(data, options).map_err(|mut e| {
+        e.offset = e.offset.map(|offset| offset + data_offset as u64);
+        e
+    })?;
+    diagnostics.extend(smf_diagnostics.into_iter().map(|mut diagnostic| {
+        diagnostic.offset = diagnostic.offset.map(|offset| offset + data_offset as u64);
+        diagnostic
+    }));
+    file.riff_info = info;
+    Ok((file, diagnostics))
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
 pub struct MidiFile {
     pub header: HeaderChunk,
     pub tracks: Vec<TrackChunk>,
+    pub riff_info: Option<RiffInfo>, // Metadata of files wrapped in a RIFF RMID container
 }
 
 impl MidiFile {
+    // The plain variants parse strictly. The diagnostics of strict parsing only concern data
+    // that was ignored, so they are dropped. The command line always passes options.
+    #[allow(dead_code)]
     pub fn read_midi(file_path: &str) -> Result<Self,MidiError> {
-        let mut file = File::open(file_path).map_err(|e|
-            MidiError { message: e.to_string(), error_type: MidiErrorType::IO }
+        Self::read_midi_with_options(file_path, &ParseOptions::strict()).map(|(file, _)| file)
+    }
+
+    pub fn read_midi_with_options(file_path: &str, options: &ParseOptions) -> Result<(Self, Vec<Diagnostic>),MidiError> {
+        let file = File::open(file_path).map_err(|e|
+            MidiError::new(MidiErrorType::IO, e.to_string())
         )?;
-        let header_chunk = read_chunk(&mut file)?;
-        if let Chunk::Header(header) = header_chunk {
-            let mut tracks = vec![];
-            for _ in 0..header.ntrks {
-                let next_chunk = read_chunk(&mut file)?;
-                if let Chunk::Track(track) = next_chunk {
-                    tracks.push(track);
-                }
+        Self::from_reader_with_options(BufReader::new(file), options)
+    }
+
+    #[allow(dead_code)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self,MidiError> {
+        Self::from_bytes_with_options(bytes, &ParseOptions::strict()).map(|(file, _)| file)
+    }
+
+    pub fn from_bytes_with_options(bytes: &[u8], options: &ParseOptions) -> Result<(Self, Vec<Diagnostic>),MidiError> {
+        parse_midi(bytes, options)
+    }
+
+    #[allow(dead_code)]
+    pub fn from_reader(reader: impl Read) -> Result<Self,MidiError> {
+        Self::from_reader_with_options(reader, &ParseOptions::strict()).map(|(file, _)| file)
+    }
+
+    // The whole input is buffered, as repairing damaged files requires searching ahead
+    pub fn from_reader_with_options(mut reader: impl Read, options: &ParseOptions) -> Result<(Self, Vec<Diagnostic>),MidiError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).map_err(|e| MidiError::new(MidiErrorType::IO, e.to_string()))?;
+        Self::from_bytes_with_options(&bytes, options)
+    }
+
+    // Absolute tick at which each track starts. Format 2 patterns follow each other,
+    // all other tracks start at the beginning of the file.
+    pub fn get_track_start_ticks(&self) -> Vec<u64> {
+        let mut start = 0;
+        self.tracks.iter().map(|track| {
+            let track_start = start;
+            if self.header.format == Format::SequenceTrack {
+                start += track.events.iter().map(|(dt, _)| *dt as u64).sum::<u64>();
             }
-            Ok(Self { header, tracks })
-        } else {
-            Err(MidiError { message: String::from("A midi file has to start with a header chunk"), error_type: MidiErrorType::InvalidMidi })
+            track_start
+        }).collect()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smf(ntrks: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut out = b"MThd".to_vec();
+        out.extend(6_u32.to_be_bytes());
+        out.extend(1_u16.to_be_bytes());
+        out.extend(ntrks.to_be_bytes());
+        out.extend(96_u16.to_be_bytes());
+        for track in tracks {
+            out.extend(b"MTrk");
+            out.extend((track.len() as u32).to_be_bytes());
+            out.extend(*track);
+        }
+        out
+    }
+
+    const NOTE: &[u8] = &[0x00, 0x90, 0x3C, 0x64, 0x60, 0x80, 0x3C, 0x40, 0x00, 0xFF, 0x2F, 0x00];
+
+    #[test]
+    fn track_count_mismatch() {
+        let input = smf(2, &[NOTE]);
+        let error = MidiFile::from_bytes_with_options(&input, &ParseOptions::strict()).unwrap_err();
+        assert_eq!(error.error_type, MidiErrorType::TrackCountMismatch);
+        let (file, diagnostics) = MidiFile::from_bytes_with_options(&input, &ParseOptions::lenient()).unwrap();
+        assert_eq!(file.header.ntrks, 1);
+        assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.kind).collect::<Vec<_>>(), [DiagnosticKind::TrackCountMismatch]);
+        assert_eq!(diagnostics[0].offset, None);
+    }
+
+    #[test]
+    fn junk_between_chunks() {
+        let mut input = smf(2, &[NOTE]);
+        input.extend([0x00, 0x00, 0x00]);
+        input.extend(&smf(1, &[NOTE])[14..]);
+        assert_eq!(MidiFile::from_bytes_with_options(&input, &ParseOptions::strict()).unwrap_err().error_type, MidiErrorType::InvalidMidi);
+        let (file, diagnostics) = MidiFile::from_bytes_with_options(&input, &ParseOptions::lenient()).unwrap();
+        assert_eq!(file.tracks.len(), 2);
+        assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.kind).collect::<Vec<_>>(), [DiagnosticKind::JunkBetweenChunks]);
+
+        // Strict mode ignores data after the announced tracks
+        let mut input = smf(1, &[NOTE]);
+        input.extend([0x00, 0x00, 0x00]);
+        let (_, diagnostics) = MidiFile::from_bytes_with_options(&input, &ParseOptions::strict()).unwrap();
+        assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.kind).collect::<Vec<_>>(), [DiagnosticKind::TrailingData]);
+    }
+
+    #[test]
+    fn missing_end_of_track_is_inserted() {
+        let input = smf(1, &[&NOTE[..8]]);
+        assert_eq!(MidiFile::from_bytes_with_options(&input, &ParseOptions::strict()).unwrap_err().error_type, MidiErrorType::TruncatedChunk);
+        let (file, diagnostics) = MidiFile::from_bytes_with_options(&input, &ParseOptions::lenient()).unwrap();
+        assert_eq!(file.tracks[0].events.len(), 3);
+        assert_eq!(file.tracks[0].events[2], (0, Event::Meta(MetaEvent::EndOfTrack)));
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::MissingEndOfTrack);
+    }
+
+    #[test]
+    fn interrupted_sysex_is_closed() {
+        // F0 packet without F7, a note on and a continuation packet that no longer belongs to it
+        let track = [&[0x00, 0xF0, 0x02, 0x41, 0x10, 0x00, 0x90, 0x3C, 0x64, 0x00, 0xF7, 0x01, 0xF7][..], &NOTE[4..]].concat();
+        for options in [ParseOptions::strict(), ParseOptions::lenient()] {
+            let (file, diagnostics) = MidiFile::from_bytes_with_options(&smf(1, &[&track]), &options).unwrap();
+            assert_eq!(file.tracks[0].events[0], (0, Event::Sysex(vec![0x41, 0x10])));
+            assert_eq!(file.tracks[0].events[2], (0, Event::Escape(vec![0xF7])));
+            assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.kind).collect::<Vec<_>>(), [DiagnosticKind::UnterminatedSysex]);
+            assert_eq!(diagnostics[0].offset, Some(22));
         }
     }
 
-    pub fn get_simult_track_count(&self) -> usize {
-        match self.header.format {
-            Format::SingleTrack => 1,
-            Format::SimulTrack => self.header.ntrks as usize,
-            Format::SequenceTrack => 1,
+    #[test]
+    fn huge_data_lengths_are_errors() {
+        // A meta event and a sysex that announce 256 MB
+        for track in [&[0x00, 0xFF, 0x01, 0xFF, 0xFF, 0xFF, 0x7F, b'a'][..], &[0x00, 0xF0, 0xFF, 0xFF, 0xFF, 0x7F, 0x7E][..]] {
+            let input = smf(1, &[track]);
+            assert_eq!(MidiFile::from_bytes_with_options(&input, &ParseOptions::strict()).unwrap_err().error_type, MidiErrorType::TruncatedChunk);
+            let (file, diagnostics) = MidiFile::from_bytes_with_options(&input, &ParseOptions::lenient()).unwrap();
+            assert_eq!(file.tracks[0].events, [(0, Event::Meta(MetaEvent::EndOfTrack))]);
+            assert_eq!(diagnostics[0].kind, DiagnosticKind::MissingEndOfTrack);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn switches_keep_their_value() {
+        let half_pedal = ControllerMessage::from_raw(0x40, 40);
+        assert_eq!(half_pedal, ControllerMessage::DamperPedalOn(40));
+        assert_eq!(half_pedal.to_raw(), (0x40, 40));
+
+        let mut values = ControllerValues::new();
+        values.update(&half_pedal);
+        assert!(!values.is_on(0x40));
+        values.update(&ControllerMessage::DamperPedalOn(64));
+        assert!(values.is_on(0x40));
+    }
+
+    #[test]
+    fn msb_resets_the_lsb() {
+        let mut values = ControllerValues::new();
+        values.update(&ControllerMessage::ChannelVolumeMSB(100));
+        values.update(&ControllerMessage::ChannelVolumeLSB(5));
+        assert_eq!(values.get_14bit(0x07), 100 << 7 | 5);
+        values.update(&ControllerMessage::ChannelVolumeMSB(90));
+        assert_eq!(values.get_14bit(0x07), 90 << 7);
+    }
+
+    #[test]
+    fn plain_readers_parse_strictly() {
+        let input = smf(1, &[NOTE]);
+        let file = MidiFile::from_bytes(&input).unwrap();
+        assert_eq!(MidiFile::from_reader(&input[..]).unwrap(), file);
+        let path = std::env::temp_dir().join(format!("plain_readers_parse_strictly_{}.mid", std::process::id()));
+        std::fs::write(&path, &input).unwrap();
+        let read = MidiFile::read_midi(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), file);
+        assert_eq!(MidiFile::read_midi("").unwrap_err().error_type, MidiErrorType::IO);
+
+        // Damaged input is not repaired
+        let truncated = smf(1, &[&NOTE[..8]]);
+        assert_eq!(MidiFile::from_bytes(&truncated).unwrap_err().error_type, MidiErrorType::TruncatedChunk);
+        assert_eq!(MidiFile::from_reader(&truncated[..]).unwrap_err().error_type, MidiErrorType::TruncatedChunk);
+    }
+}
@@ -0,0 +1,292 @@
+
+use std::io::{Write, BufWriter};
+use std::fs::File;
+
+use crate::midi_parser::{MidiFile, MidiError, MidiErrorType, Format, Division, TrackChunk, Event, MidiEvent, MetaEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    pub running_status: bool // Omit status bytes that repeat the previous status
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self { running_status: true }
+    }
+}
+
+// Write a variable length quantity as used in midi files. Only 28 bits can be represented.
+fn write_vlq(out: &mut Vec<u8>, value: u32) -> Result<(), MidiError> {
+    if value > 0x0FFF_FFFF {
+        return Err(MidiError::new(MidiErrorType::InvalidVlq, format!("{} does not fit into a variable length quantity", value)));
+    }
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        groups.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(groups.iter().rev());
+    Ok(())
+}
+
+fn midi_event_bytes(event: &MidiEvent) -> (u8, Vec<u8>) {
+    match event {
+        MidiEvent::NoteOff { key, velocity } => (0b1000, vec![*key, *velocity]),
+        MidiEvent::NoteOn { key, velocity } => (0b1001, vec![*key, *velocity]),
+        MidiEvent::PolyphonicKeyPressure { key, pressure } => (0b1010, vec![*key, *pressure]),
+        MidiEvent::ControlChange(message) => {
+            let (number, value) = message.to_raw();
+            (0b1011, vec![number, value])
+        },
+        MidiEvent::ProgramChange(program) => (0b1100, vec![*program]),
+        MidiEvent::ChannelPressure(pressure) => (0b1101, vec![*pressure]),
+        MidiEvent::PitchWheelChange(pitch) => (0b1110, vec![(pitch & 0x7F) as u8, (pitch >> 7 & 0x7F) as u8]),
+    }
+}
+
+fn meta_event_bytes(event: &MetaEvent) -> (u8, Vec<u8>) {
+    match event {
+        MetaEvent::SequenceNumber { number } => (0x00, number.map(|n| n.to_be_bytes().to_vec()).unwrap_or_default()),
+        MetaEvent::Text { text } => (0x01, text.raw().to_vec()),
+        MetaEvent::CopyrightNotice { text } => (0x02, text.raw().to_vec()),
+        MetaEvent::SequenceTrackName { text } => (0x03, text.raw().to_vec()),
+        MetaEvent::InstrumentName { text } => (0x04, text.raw().to_vec()),
+        MetaEvent::Lyric { text } => (0x05, text.raw().to_vec()),
+        MetaEvent::Marker { text } => (0x06, text.raw().to_vec()),
+        MetaEvent::CuePoint { text } => (0x07, text.raw().to_vec()),
+        MetaEvent::ProgramName { text } => (0x08, text.raw().to_vec()),
+        MetaEvent::DeviceName { text } => (0x09, text.raw().to_vec()),
+        MetaEvent::MidiChannelPrefix { channel } => (0x20, vec![*channel]),
+        MetaEvent::MidiPort { port } => (0x21, vec![*port]),
+        MetaEvent::EndOfTrack => (0x2F, vec![]),
+        MetaEvent::SetTempo { tempo } => (0x51, tempo.to_be_bytes()[1..].to_vec()),
+        MetaEvent::SmpteOffset { fps, hours, minutes, seconds, frames, fractional_frames } => {
+            let rate_code = match fps {
+                24 => 0,
+                25 => 1,
+                29 => 2,
+                _ => 3
+            };
+            (0x54, vec![rate_code << 5 | (hours & 0b1_1111), *minutes, *seconds, *frames, *fractional_frames])
+        },
+        MetaEvent::TimeSignature { numerator, denominator, metronome_clocks, notated_32s_per_quarter } => {
+            (0x58, vec![*numerator, denominator.trailing_zeros() as u8, *metronome_clocks, *notated_32s_per_quarter])
+        },
+        MetaEvent::KeySignature { sharps_flats, minor } => (0x59, vec![*sharps_flats as u8, *minor as u8]),
+        MetaEvent::SequencerSpecific { data } => (0x7F, data.clone()),
+        MetaEvent::Unknown { meta_type, data } => (*meta_type, data.clone()),
+    }
+}
+
+fn track_bytes(track: &TrackChunk, options: &WriteOptions) -> Result<Vec<u8>, MidiError> {
+    let mut out = Vec::new();
+    let mut last_status = None;
+    for (index, (dt, event)) in track.events.iter().enumerate() {
+        let at_event = |e: MidiError| e.at_event(index);
+        write_vlq(&mut out, *dt).map_err(at_event)?;
+        match event {
+            Event::Midi(channel, midi_event) => {
+                let (event_type, data) = midi_event_bytes(midi_event);
+                let status = event_type << 4 | (channel & 0xF);
+                if !options.running_status || last_status != Some(status) {
+                    out.push(status);
+                }
+                last_status = Some(status);
+                out.extend(data.iter().map(|byte| byte & 0x7F));
+            },
+            // Sysex and meta events cancel the running status
+            Event::Sysex(data) => {
+                out.push(0xF0);
+                write_vlq(&mut out, data.len() as u32 + 1).map_err(at_event)?;
+                out.extend(data);
+                out.push(0xF7);
+                last_status = None;
+            },
+            Event::Escape(data) => {
+                out.push(0xF7);
+                write_vlq(&mut out, data.len() as u32).map_err(at_event)?;
+                out.extend(data);
+                last_status = None;
+            },
+            Event::Meta(meta_event) => {
+                let (meta_type, data) = meta_event_bytes(meta_event);
+                out.push(0xFF);
+                out.push(meta_type);
+                write_vlq(&mut out, data.len() as u32).map_err(at_event)?;
+                out.extend(data);
+                last_status = None;
+            }
+        }
+    }
+
+    // Every track has to be terminated
+    if !matches!(track.events.last(), Some((_, Event::Meta(MetaEvent::EndOfTrack)))) {
+        out.extend([0x00, 0xFF, 0x2F, 0x00]);
+    }
+    Ok(out)
+}
+
+impl MidiFile {
+    // The track count of the header is taken from the tracks, so that a header that went out
+    // of sync with them still results in a valid file
+    pub fn to_bytes(&self, options: &WriteOptions) -> Result<Vec<u8>, MidiError> {
+        let mut out = Vec::new();
+
+        out.extend(b"MThd");
+        out.extend(6_u32.to_be_bytes());
+        let format: u16 = match self.header.format {
+            Format::SingleTrack => 0,
+            Format::SimulTrack => 1,
+            Format::SequenceTrack => 2,
+        };
+        out.extend(format.to_be_bytes());
+        out.extend((self.tracks.len() as u16).to_be_bytes());
+        match self.header.division {
+            Division::TicksPerQuarter(ticks) => out.extend((ticks as u16 & 0x7FFF).to_be_bytes()),
+            Division::TicksPerFrame(fps, ticks) => out.extend([(-(fps as i8)) as u8, ticks as u8]),
+        }
+
+        for (index, track) in self.tracks.iter().enumerate() {
+            let data = track_bytes(track, options).map_err(|e| e.in_track(index))?;
+            out.extend(b"MTrk");
+            out.extend((data.len() as u32).to_be_bytes());
+            out.extend(data);
+        }
+        Ok(out)
+    }
+
+    pub fn to_writer(&self, mut writer: impl Write, options: &WriteOptions) -> Result<(), MidiError> {
+        let bytes = self.to_bytes(options)?;
+        writer.write_all(&bytes).and_then(|_| writer.flush()).map_err(|e|
+            MidiError::new(MidiErrorType::IO, e.to_string())
+        )
+    }
+
+    pub fn write_midi(&self, file_path: &str, options: &WriteOptions) -> Result<(), MidiError> {
+        let file = File::create(file_path).map_err(|e|
+            MidiError::new(MidiErrorType::IO, e.to_string())
+        )?;
+        self.to_writer(BufWriter::new(file), options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{ControllerMessage, SysexMessage, ParseOptions};
+
+    fn smf(format: u16, division: [u8; 2], tracks: &[&[u8]]) -> Vec<u8> {
+        let mut out = b"MThd".to_vec();
+        out.extend(6_u32.to_be_bytes());
+        out.extend(format.to_be_bytes());
+        out.extend((tracks.len() as u16).to_be_bytes());
+        out.extend(division);
+        for track in tracks {
+            out.extend(b"MTrk");
+            out.extend((track.len() as u32).to_be_bytes());
+            out.extend(*track);
+        }
+        out
+    }
+
+    const CONDUCTOR: &[u8] = &[
+        0x00, 0xFF, 0x00, 0x02, 0x00, 0x07, // Sequence Number
+        0x00, 0xFF, 0x00, 0x00, // Sequence Number without a number
+        0x00, 0xFF, 0x03, 0x05, b'T', b'e', b'm', b'p', b'o',
+        0x00, 0xFF, 0x02, 0x04, b'(', b'c', b')', 0xA9, // Not valid UTF-8
+        0x00, 0xFF, 0x54, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05, // SMPTE Offset at 24 fps
+        0x00, 0xFF, 0x54, 0x05, 0x21, 0x02, 0x03, 0x04, 0x05, // 25 fps
+        0x00, 0xFF, 0x54, 0x05, 0x41, 0x02, 0x03, 0x04, 0x05, // 30 drop frame
+        0x00, 0xFF, 0x54, 0x05, 0x61, 0x02, 0x03, 0x04, 0x05, // 30 fps
+        0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+        0x00, 0xFF, 0x58, 0x04, 0x06, 0x03, 0x24, 0x08,
+        0x00, 0xFF, 0x59, 0x02, 0xFD, 0x01,
+        0x00, 0xFF, 0x06, 0x05, b'V', b'e', b'r', b's', b'e',
+        0x00, 0xFF, 0x07, 0x03, b'C', b'u', b'e',
+        0x00, 0xFF, 0x60, 0x03, 0x01, 0x02, 0x03, // Unknown meta event
+        0x00, 0xFF, 0x7F, 0x03, 0x00, 0x00, 0x41,
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+
+    const EVENTS: &[u8] = &[
+        0x00, 0xFF, 0x20, 0x01, 0x00,
+        0x00, 0xFF, 0x21, 0x01, 0x01,
+        0x00, 0xFF, 0x04, 0x05, b'P', b'i', b'a', b'n', b'o',
+        0x00, 0xFF, 0x08, 0x03, b'P', b'n', b'o',
+        0x00, 0xFF, 0x09, 0x03, b'O', b'u', b't',
+        0x00, 0xF0, 0x05, 0x7E, 0x7F, 0x09, 0x01, 0xF7, // GM System On
+        0x00, 0xF0, 0x03, 0x43, 0x10, 0x4C, // Sysex that is continued by the next two packets
+        0x10, 0xF7, 0x02, 0x00, 0x00,
+        0x10, 0xF7, 0x03, 0x7E, 0x00, 0xF7,
+        0x00, 0xF7, 0x02, 0xF3, 0x01, // Escape
+        0x00, 0xB0, 0x07, 0x64,
+        0x00, 0x60, 0x05, // Data Increment with a data byte, running status
+        0x00, 0x7E, 0x04, // Mono mode on with 4 channels
+        0x00, 0x40, 0x7F,
+        0x00, 0xC9, 0x30,
+        0x00, 0xD9, 0x40,
+        0x00, 0xE0, 0x00, 0x40,
+        0x00, 0x90, 0x3C, 0x64,
+        0x10, 0x3E, 0x64,
+        0x00, 0xA0, 0x3C, 0x20,
+        0x10, 0x80, 0x3C, 0x40,
+        0x00, 0x3E, 0x00,
+        0x00, 0xFF, 0x05, 0x02, 0x82, 0xA0, // Shift-JIS lyric
+        0x00, 0xFF, 0x01, 0x04, b'C', b'a', b'f', 0xE9, // Latin-1 text
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+
+    fn parse(input: &[u8]) -> MidiFile {
+        MidiFile::from_bytes_with_options(input, &ParseOptions::strict()).unwrap().0
+    }
+
+    fn assert_round_trip(input: &[u8]) -> MidiFile {
+        let file = parse(input);
+        let with_running_status = file.to_bytes(&WriteOptions { running_status: true }).unwrap();
+        let without_running_status = file.to_bytes(&WriteOptions { running_status: false }).unwrap();
+        assert!(with_running_status.len() < without_running_status.len());
+        assert_eq!(parse(&with_running_status), file);
+        assert_eq!(parse(&without_running_status), file);
+        file
+    }
+
+    #[test]
+    fn round_trip_keeps_every_event() {
+        let file = assert_round_trip(&smf(1, [0x01, 0xE0], &[CONDUCTOR, EVENTS]));
+        let events = file.tracks[1].events.iter().map(|(_, event)| event).collect::<Vec<_>>();
+        assert!(events.contains(&&Event::Sysex(vec![0x43, 0x10, 0x4C, 0x00, 0x00, 0x7E, 0x00])));
+        assert!(events.contains(&&Event::Escape(vec![0xF3, 0x01])));
+        assert!(events.contains(&&Event::Midi(0, MidiEvent::ControlChange(ControllerMessage::PolyModeOnOffAllNotesOff(4)))));
+        assert!(events.iter().any(|event| matches!(event, Event::Sysex(data) if SysexMessage::parse(data) == SysexMessage::XgSystemOn)));
+        let fps = file.tracks[0].events.iter().filter_map(|(_, event)| match event {
+            Event::Meta(MetaEvent::SmpteOffset { fps, .. }) => Some(*fps),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(fps, [24, 25, 29, 30]);
+    }
+
+    #[test]
+    fn round_trip_keeps_delta_times_of_continued_sysex() {
+        let file = assert_round_trip(&smf(0, [0x00, 0x60], &[EVENTS]));
+        let escape_tick = file.tracks[0].events.iter().find(|(_, event)| matches!(event, Event::Escape(_))).map(|(dt, _)| *dt);
+        assert_eq!(escape_tick, Some(0x20));
+    }
+
+    #[test]
+    fn round_trip_keeps_smpte_division() {
+        let file = assert_round_trip(&smf(0, [0xE3, 0x50], &[EVENTS]));
+        assert_eq!(file.header.division, Division::TicksPerFrame(29, 80));
+    }
+
+    #[test]
+    fn delta_times_beyond_28_bits_are_errors() {
+        let mut file = parse(&smf(0, [0x00, 0x60], &[EVENTS]));
+        file.tracks[0].events[2].0 = 0x0FFF_FFFF;
+        assert!(file.to_bytes(&WriteOptions::new()).is_ok());
+        file.tracks[0].events[2].0 = 0x1000_0000;
+        let error = file.to_bytes(&WriteOptions::new()).unwrap_err();
+        assert_eq!(error.error_type, MidiErrorType::InvalidVlq);
+        assert_eq!((error.track, error.event), (Some(0), Some(2)));
+    }
+}
@@ -0,0 +1,169 @@
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::midi_parser::{MidiFile, TrackChunk, Event, MidiEvent, MetaEvent};
+use crate::tempo_map::TempoMap;
+use crate::ports::{PortMap, ChannelId};
+use crate::patches::{Patch, PatchMap, SoundSet};
+use crate::channel_state::ChannelState;
+
+// Decides which note is ended when a key is released that is held several times on the same channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteOverlap {
+    Fifo, // The note that started first
+    Lifo, // The note that started last
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Note {
+    pub track: usize,
+    pub port: u8,
+    pub channel: u8,
+    pub key: u8,
+    pub velocity: u8,
+    pub off_velocity: Option<u8>, // None: ended by a note on with velocity 0 or not ended at all
+    pub start: u64, // Absolute ticks
+    pub end: u64,
+    pub start_seconds: f64,
+    pub end_seconds: f64,
+    pub unterminated: bool, // No note off was found, the note ends with the track
+    pub patch: Patch,
+}
+
+impl Note {
+    pub fn channel_id(&self) -> ChannelId {
+        ChannelId::new(self.port, self.channel)
+    }
+}
+
+impl TrackChunk {
+    // Pairs note ons with note offs. `track` is the index of this track inside `tempo_map`'s file,
+    // `patches` has the patch of every note on of the track. Notes beyond its end get the
+    // General MIDI patch that their channel starts with.
+    pub fn notes(&self, track: usize, tempo_map: &TempoMap, port_map: &PortMap, patches: &[Patch], overlap: NoteOverlap) -> Vec<Note> {
+        let mut notes: Vec<Note> = Vec::new();
+        let mut open_notes = HashMap::<(u8, u8, u8), VecDeque<usize>>::new(); // Indices into `notes` per (port, channel, key)
+
+        let mut tick = tempo_map.track_start(track);
+        let mut port = 0;
+        for (dt, event) in self.events.iter() {
+            tick += *dt as u64;
+            port = port_map.next_port(port, event);
+            let (channel, key, off_velocity) = match event {
+                Event::Midi(channel, MidiEvent::NoteOn { key, velocity: 0 }) => (*channel, *key, None),
+                Event::Midi(channel, MidiEvent::NoteOff { key, velocity }) => (*channel, *key, Some(*velocity)),
+                Event::Midi(channel, MidiEvent::NoteOn { key, velocity }) => {
+                    open_notes.entry((port, *channel, *key)).or_default().push_back(notes.len());
+                    notes.push(Note {
+                        track, port, channel: *channel, key: *key, velocity: *velocity, off_velocity: None,
+                        start: tick, end: tick, start_seconds: 0.0, end_seconds: 0.0, unterminated: true,
+                        patch: patches.get(notes.len()).copied().unwrap_or_else(|| Patch::new(*channel, &ChannelState::new(), SoundSet::Gm1))
+                    });
+                    continue;
+                },
+                Event::Meta(MetaEvent::EndOfTrack) => break,
+                _ => continue
+            };
+
+            let held = open_notes.entry((port, channel, key)).or_default();
+            let index = match overlap {
+                NoteOverlap::Fifo => held.pop_front(),
+                NoteOverlap::Lifo => held.pop_back(),
+            };
+            // Note offs without a matching note on are ignored
+            if let Some(index) = index {
+                notes[index].end = tick;
+                notes[index].off_velocity = off_velocity;
+                notes[index].unterminated = false;
+            }
+        }
+
+        // Notes that are still held are ended at the End of Track event
+        for note in notes.iter_mut() {
+            if note.unterminated {
+                note.end = tick;
+            }
+            note.start_seconds = tempo_map.ticks_to_seconds(note.start);
+            note.end_seconds = tempo_map.ticks_to_seconds(note.end);
+        }
+        notes
+    }
+}
+
+impl MidiFile {
+    // Notes of all tracks, ordered by their start, with the patch that plays them
+    pub fn notes(&self, overlap: NoteOverlap) -> Vec<Note> {
+        let tempo_map = TempoMap::new(self);
+        let port_map = PortMap::new(self);
+        let patch_map = PatchMap::new(self);
+        let mut notes = self.tracks.iter().enumerate()
+            .flat_map(|(track_index, track)| track.notes(track_index, &tempo_map, &port_map, patch_map.note_patches(track_index), overlap))
+            .collect::<Vec<_>>();
+        notes.sort_by_key(|note| note.start);
+        notes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{HeaderChunk, Format, Division};
+
+    fn file(events: Vec<(u32, Event)>) -> MidiFile {
+        let header = HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: Division::TicksPerQuarter(96) };
+        MidiFile { header, tracks: vec![TrackChunk { events }], riff_info: None }
+    }
+
+    fn on(dt: u32, velocity: u8) -> (u32, Event) {
+        (dt, Event::Midi(0, MidiEvent::NoteOn { key: 60, velocity }))
+    }
+
+    fn off(dt: u32, velocity: u8) -> (u32, Event) {
+        (dt, Event::Midi(0, MidiEvent::NoteOff { key: 60, velocity }))
+    }
+
+    fn intervals(notes: &[Note]) -> Vec<(u8, u64, u64, Option<u8>)> {
+        notes.iter().map(|note| (note.velocity, note.start, note.end, note.off_velocity)).collect()
+    }
+
+    #[test]
+    fn overlapping_notes_of_a_key() {
+        let file = file(vec![on(0, 100), on(10, 50), off(10, 1), off(20, 2), (0, Event::Meta(MetaEvent::EndOfTrack))]);
+        assert_eq!(intervals(&file.notes(NoteOverlap::Fifo)), [(100, 0, 20, Some(1)), (50, 10, 40, Some(2))]);
+        assert_eq!(intervals(&file.notes(NoteOverlap::Lifo)), [(100, 0, 40, Some(2)), (50, 10, 20, Some(1))]);
+    }
+
+    #[test]
+    fn zero_velocity_and_unterminated_notes() {
+        let file = file(vec![on(0, 100), on(48, 0), on(0, 80), (96, Event::Meta(MetaEvent::EndOfTrack))]);
+        let notes = file.notes(NoteOverlap::Fifo);
+        assert_eq!(intervals(&notes), [(100, 0, 48, None), (80, 48, 144, None)]);
+        assert_eq!(notes.iter().map(|note| note.unterminated).collect::<Vec<_>>(), [false, true]);
+        assert_eq!(notes[1].end_seconds, 0.75);
+    }
+
+    #[test]
+    fn program_changes_apply_in_event_order() {
+        let program = |dt, program| (dt, Event::Midi(0, MidiEvent::ProgramChange(program)));
+        let file = file(vec![
+            program(0, 5), on(0, 100), program(0, 40), on(10, 90),
+            (0, Event::Midi(9, MidiEvent::NoteOn { key: 36, velocity: 100 })),
+            (0, Event::Meta(MetaEvent::EndOfTrack)),
+        ]);
+        let notes = file.notes(NoteOverlap::Fifo);
+        assert_eq!(notes.iter().map(|note| (note.patch.program, note.patch.drum)).collect::<Vec<_>>(), [(5, false), (40, false), (0, true)]);
+    }
+
+    #[test]
+    fn missing_patches_fall_back_to_the_initial_patch() {
+        let file = file(vec![on(0, 100), (0, Event::Midi(9, MidiEvent::NoteOn { key: 36, velocity: 100 })), (0, Event::Meta(MetaEvent::EndOfTrack))]);
+        let tempo_map = TempoMap::new(&file);
+        let port_map = PortMap::new(&file);
+        let piano = Patch::new(0, &ChannelState::new(), SoundSet::Gm1);
+        let organ = Patch { program: 16, ..piano };
+        let patches = |patches: &[Patch]| file.tracks[0].notes(0, &tempo_map, &port_map, patches, NoteOverlap::Fifo)
+            .iter().map(|note| (note.patch.program, note.patch.drum)).collect::<Vec<_>>();
+        assert_eq!(patches(&[]), [(0, false), (0, true)]);
+        assert_eq!(patches(&[organ]), [(16, false), (0, true)]);
+    }
+}
@@ -0,0 +1,254 @@
+
+use crate::midi_parser::ControllerMessage;
+
+pub const NULL_PARAMETER: u16 = 0x3FFF; // RPN 127/127 deselects the parameter
+pub const CENTER: u16 = 0x2000; // Center of 14 bit values, also of the pitch wheel
+
+// 14 bit parameter numbers, MSB << 7 | LSB
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ParameterNumber {
+    Registered(u16),
+    NonRegistered(u16),
+}
+
+impl ParameterNumber {
+    // Parameters that only use the Data Entry MSB, so that increments step the MSB
+    fn is_coarse(&self) -> bool {
+        match self {
+            ParameterNumber::Registered(number) => matches!(number, 0x0002..=0x0004),
+            ParameterNumber::NonRegistered(_) => true,
+        }
+    }
+
+    // Value of the parameter before it is set for the first time
+    fn default_value(&self) -> u16 {
+        match self {
+            ParameterNumber::Registered(0x0000) => 2 << 7, // 2 semitones
+            ParameterNumber::Registered(0x0005) => 64, // 50 cents
+            ParameterNumber::Registered(0x0003) | ParameterNumber::Registered(0x0004) => 0,
+            _ => CENTER,
+        }
+    }
+}
+
+// Typed meaning of a parameter value. Values of GS/XG NRPNs are relative to their default, -64..=63.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParameterChange {
+    PitchBendSensitivity { semitones: u8, cents: u8 },
+    FineTuning(f64), // Cents, -100.0..100.0
+    CoarseTuning(i8), // Semitones
+    TuningProgramSelect(u8),
+    TuningBankSelect(u8),
+    ModulationDepthRange { semitones: u8, cents: f64 },
+    VibratoRate(i8),
+    VibratoDepth(i8),
+    VibratoDelay(i8),
+    FilterCutoff(i8),
+    FilterResonance(i8),
+    EnvelopeAttack(i8),
+    EnvelopeDecay(i8),
+    EnvelopeRelease(i8),
+    DrumPitchCoarse { key: u8, value: i8 },
+    DrumPitchFine { key: u8, value: i8 },
+    DrumLevel { key: u8, value: u8 },
+    DrumPan { key: u8, value: i8 },
+    DrumReverb { key: u8, value: u8 },
+    DrumChorus { key: u8, value: u8 },
+    DrumVariation { key: u8, value: u8 },
+    Registered { number: u16, value: u16 }, // Parameters without a typed meaning
+    NonRegistered { number: u16, value: u16 },
+}
+
+impl ParameterChange {
+    pub fn new(parameter: ParameterNumber, value: u16) -> Self {
+        let msb = (value >> 7) as u8;
+        let lsb = (value & 0x7F) as u8;
+        let relative = msb as i8 - 64;
+        match parameter {
+            ParameterNumber::Registered(0x0000) => ParameterChange::PitchBendSensitivity { semitones: msb, cents: lsb },
+            ParameterNumber::Registered(0x0001) => ParameterChange::FineTuning((value as f64 - CENTER as f64) / CENTER as f64 * 100.0),
+            ParameterNumber::Registered(0x0002) => ParameterChange::CoarseTuning(relative),
+            ParameterNumber::Registered(0x0003) => ParameterChange::TuningProgramSelect(msb),
+            ParameterNumber::Registered(0x0004) => ParameterChange::TuningBankSelect(msb),
+            ParameterNumber::Registered(0x0005) => ParameterChange::ModulationDepthRange { semitones: msb, cents: lsb as f64 * 100.0 / 128.0 },
+            ParameterNumber::Registered(number) => ParameterChange::Registered { number, value },
+            // GS and XG use the same numbers for the sound parameters of a part
+            ParameterNumber::NonRegistered(0x0088) => ParameterChange::VibratoRate(relative),
+            ParameterNumber::NonRegistered(0x0089) => ParameterChange::VibratoDepth(relative),
+            ParameterNumber::NonRegistered(0x008A) => ParameterChange::VibratoDelay(relative),
+            ParameterNumber::NonRegistered(0x00A0) => ParameterChange::FilterCutoff(relative),
+            ParameterNumber::NonRegistered(0x00A1) => ParameterChange::FilterResonance(relative),
+            ParameterNumber::NonRegistered(0x00E3) => ParameterChange::EnvelopeAttack(relative),
+            ParameterNumber::NonRegistered(0x00E4) => ParameterChange::EnvelopeDecay(relative),
+            ParameterNumber::NonRegistered(0x00E6) => ParameterChange::EnvelopeRelease(relative),
+            // Drum instruments: the LSB of the parameter number is the key
+            ParameterNumber::NonRegistered(number) => {
+                let key = (number & 0x7F) as u8;
+                match number >> 7 {
+                    0x18 => ParameterChange::DrumPitchCoarse { key, value: relative },
+                    0x19 => ParameterChange::DrumPitchFine { key, value: relative },
+                    0x1A => ParameterChange::DrumLevel { key, value: msb },
+                    0x1C => ParameterChange::DrumPan { key, value: relative },
+                    0x1D => ParameterChange::DrumReverb { key, value: msb },
+                    0x1E => ParameterChange::DrumChorus { key, value: msb },
+                    0x1F => ParameterChange::DrumVariation { key, value: msb },
+                    _ => ParameterChange::NonRegistered { number, value },
+                }
+            }
+        }
+    }
+}
+
+// Follows the RPN/NRPN state machine of a channel: a parameter is selected with the (Non-)Registered
+// Parameter Number controllers and then changed with Data Entry, Data Increment and Data Decrement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterState {
+    rpn: u16,
+    nrpn: u16,
+    selected: Option<ParameterNumber>,
+    values: Vec<(ParameterNumber, u16)>, // Parameters that were set
+}
+
+impl ParameterState {
+    pub fn new() -> Self {
+        Self { rpn: NULL_PARAMETER, nrpn: NULL_PARAMETER, selected: None, values: Vec::new() }
+    }
+
+    pub fn value(&self, parameter: ParameterNumber) -> u16 {
+        self.values.iter().find(|(number, _)| *number == parameter).map_or(parameter.default_value(), |(_, value)| *value)
+    }
+
+    fn set(&mut self, parameter: ParameterNumber, value: u16) -> ParameterChange {
+        match self.values.iter_mut().find(|(number, _)| *number == parameter) {
+            Some((_, old_value)) => *old_value = value,
+            None => self.values.push((parameter, value)),
+        }
+        ParameterChange::new(parameter, value)
+    }
+
+    // Returns the change of a parameter caused by the message
+    pub fn update(&mut self, message: &ControllerMessage) -> Option<ParameterChange> {
+        let select = |number: u16, msb: Option<u8>, lsb: Option<u8>| {
+            let msb = msb.map_or(number & 0x3F80, |msb| (msb as u16 & 0x7F) << 7);
+            let lsb = lsb.map_or(number & 0x7F, |lsb| lsb as u16 & 0x7F);
+            msb | lsb
+        };
+        match *message {
+            ControllerMessage::RegisteredParameterNumberMSB(msb) => self.rpn = select(self.rpn, Some(msb), None),
+            ControllerMessage::RegisteredParameterNumberLSB(lsb) => self.rpn = select(self.rpn, None, Some(lsb)),
+            ControllerMessage::NonRegisteredParameterNumberMSB(msb) => self.nrpn = select(self.nrpn, Some(msb), None),
+            ControllerMessage::NonRegisteredParameterNumberLSB(lsb) => self.nrpn = select(self.nrpn, None, Some(lsb)),
+            ControllerMessage::DataEntryMSB(msb) => {
+                let parameter = self.selected?;
+                return Some(self.set(parameter, (msb as u16 & 0x7F) << 7));
+            },
+            ControllerMessage::DataEntryLSB(lsb) => {
+                let parameter = self.selected?;
+                let value = (self.value(parameter) & 0x3F80) | (lsb as u16 & 0x7F);
+                return Some(self.set(parameter, value));
+            },
+            ControllerMessage::DataIncrement(_) | ControllerMessage::DataDecrement(_) => {
+                let parameter = self.selected?;
+                let step = if parameter.is_coarse() { 1 << 7 } else { 1 };
+                let value = self.value(parameter) as i32;
+                let value = if matches!(message, ControllerMessage::DataIncrement(_)) { value + step } else { value - step };
+                return Some(self.set(parameter, value.clamp(0, 0x3FFF) as u16));
+            },
+            ControllerMessage::ResetAllControllers(_) => {
+                self.rpn = NULL_PARAMETER;
+                self.nrpn = NULL_PARAMETER;
+                self.selected = None;
+                return None;
+            },
+            _ => return None
+        }
+        // The number that was sent last selects the parameter
+        self.selected = match message {
+            ControllerMessage::RegisteredParameterNumberMSB(_) | ControllerMessage::RegisteredParameterNumberLSB(_) => {
+                Some(ParameterNumber::Registered(self.rpn)).filter(|_| self.rpn != NULL_PARAMETER)
+            },
+            _ => Some(ParameterNumber::NonRegistered(self.nrpn)).filter(|_| self.nrpn != NULL_PARAMETER),
+        };
+        None
+    }
+
+    // Range of the pitch wheel in semitones
+    pub fn pitch_bend_range(&self) -> f64 {
+        let value = self.value(ParameterNumber::Registered(0x0000));
+        (value >> 7) as f64 + (value & 0x7F) as f64 / 100.0
+    }
+
+    // Detuning of the channel in semitones by fine and coarse tuning
+    pub fn tuning(&self) -> f64 {
+        let fine = (self.value(ParameterNumber::Registered(0x0001)) as f64 - CENTER as f64) / CENTER as f64;
+        let coarse = (self.value(ParameterNumber::Registered(0x0002)) >> 7) as f64 - 64.0;
+        coarse + fine
+    }
+
+    // Detuning of the channel in semitones for a position of the pitch wheel (0-16383)
+    pub fn pitch_offset(&self, pitch_wheel: u32) -> f64 {
+        (pitch_wheel as f64 - CENTER as f64) / CENTER as f64 * self.pitch_bend_range() + self.tuning()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ControllerMessage::*;
+
+    fn send(state: &mut ParameterState, messages: &[ControllerMessage]) -> Vec<ParameterChange> {
+        messages.iter().filter_map(|message| state.update(message)).collect()
+    }
+
+    #[test]
+    fn parameter_numbers_can_be_sent_in_any_order() {
+        let mut state = ParameterState::new();
+        assert_eq!(send(&mut state, &[NonRegisteredParameterNumberLSB(0x20), NonRegisteredParameterNumberMSB(0x01), DataEntryMSB(80)]), [ParameterChange::FilterCutoff(16)]);
+        assert_eq!(send(&mut state, &[NonRegisteredParameterNumberMSB(0x01), NonRegisteredParameterNumberLSB(0x08), DataEntryMSB(60)]), [ParameterChange::VibratoRate(-4)]);
+        // The kind of number that was sent last selects the parameter
+        assert_eq!(send(&mut state, &[RegisteredParameterNumberMSB(0), RegisteredParameterNumberLSB(0), NonRegisteredParameterNumberLSB(0x08), DataEntryMSB(70)]), [ParameterChange::VibratoRate(6)]);
+        assert_eq!(send(&mut state, &[RegisteredParameterNumberLSB(0), DataEntryMSB(12), DataEntryLSB(50)]), [
+            ParameterChange::PitchBendSensitivity { semitones: 12, cents: 0 },
+            ParameterChange::PitchBendSensitivity { semitones: 12, cents: 50 },
+        ]);
+        assert_eq!(state.pitch_bend_range(), 12.5);
+    }
+
+    #[test]
+    fn null_parameter_ignores_data_entry() {
+        let mut state = ParameterState::new();
+        assert_eq!(send(&mut state, &[DataEntryMSB(12)]), []);
+        assert_eq!(send(&mut state, &[RegisteredParameterNumberMSB(0), RegisteredParameterNumberLSB(0), RegisteredParameterNumberMSB(127), RegisteredParameterNumberLSB(127)]), []);
+        assert_eq!(send(&mut state, &[DataEntryMSB(12), DataIncrement(0), DataDecrement(0)]), []);
+        assert_eq!(state.pitch_bend_range(), 2.0);
+    }
+
+    #[test]
+    fn increment_steps_the_msb_of_coarse_parameters() {
+        let mut state = ParameterState::new();
+        // Pitch bend sensitivity is fine, the LSB holds the cents
+        assert_eq!(send(&mut state, &[RegisteredParameterNumberMSB(0), RegisteredParameterNumberLSB(0), DataIncrement(0)]), [ParameterChange::PitchBendSensitivity { semitones: 2, cents: 1 }]);
+        assert_eq!(send(&mut state, &[DataDecrement(0), DataDecrement(0)]), [
+            ParameterChange::PitchBendSensitivity { semitones: 2, cents: 0 },
+            ParameterChange::PitchBendSensitivity { semitones: 1, cents: 127 },
+        ]);
+        // Coarse tuning only uses the MSB
+        assert_eq!(send(&mut state, &[RegisteredParameterNumberLSB(2), DataIncrement(0), DataIncrement(0), DataDecrement(0)]), [
+            ParameterChange::CoarseTuning(1),
+            ParameterChange::CoarseTuning(2),
+            ParameterChange::CoarseTuning(1),
+        ]);
+        assert_eq!(state.tuning(), 1.0);
+        // Values stay in 14 bits
+        assert_eq!(send(&mut state, &[DataEntryMSB(127), DataIncrement(0)]), [ParameterChange::CoarseTuning(63), ParameterChange::CoarseTuning(63)]);
+    }
+
+    #[test]
+    fn reset_all_controllers_deselects_the_parameter() {
+        let mut state = ParameterState::new();
+        assert_eq!(send(&mut state, &[RegisteredParameterNumberMSB(0), RegisteredParameterNumberLSB(0), DataEntryMSB(12), ResetAllControllers(0)]).len(), 1);
+        assert_eq!(send(&mut state, &[DataEntryMSB(24), DataIncrement(0)]), []);
+        // The values of the parameters are kept
+        assert_eq!(state.pitch_bend_range(), 12.0);
+    }
+}
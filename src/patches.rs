@@ -0,0 +1,210 @@
+
+use std::collections::HashMap;
+
+use crate::midi_parser::{MidiFile, Event, MidiEvent, ControllerMessage, SysexMessage};
+use crate::channel_state::{ChannelStates, ChannelState};
+use crate::ports::{PortTracker, ChannelId};
+use crate::transform::DRUM_CHANNEL;
+use crate::instrument_names::{GM1_NAMES, GM2_VARIATIONS, GS_CAPITAL_NAMES, GS_VARIATIONS, DRUM_KITS, XG_DRUM_KITS, XG_SFX_KITS, XG_SFX_VOICES};
+
+const GM2_MELODIC_BANK: u8 = 121;
+const GM2_DRUM_BANK: u8 = 120;
+const XG_SFX_VOICE_BANK: u8 = 64;
+const XG_SFX_KIT_BANK: u8 = 126;
+const XG_DRUM_BANK: u8 = 127;
+
+// Sound set a file was written for. It decides how bank numbers and names are interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundSet {
+    Gm1,
+    Gm2,
+    Gs,
+    Xg,
+}
+
+impl SoundSet {
+    // The first system reset of the file decides. Without one the selected banks are used as a hint.
+    pub fn detect(file: &MidiFile) -> Self {
+        let mut banks = Vec::new();
+        for (_, _, event) in file.merged_events() {
+            match event {
+                Event::Sysex(data) => match SysexMessage::parse(data) {
+                    SysexMessage::GmSystemOn => return SoundSet::Gm1,
+                    SysexMessage::Gm2SystemOn => return SoundSet::Gm2,
+                    SysexMessage::GsReset => return SoundSet::Gs,
+                    SysexMessage::XgSystemOn => return SoundSet::Xg,
+                    _ => {}
+                },
+                Event::Midi(_, MidiEvent::ControlChange(ControllerMessage::BankSelectMSB(bank))) => banks.push(*bank),
+                _ => {}
+            }
+        }
+        if banks.iter().any(|bank| *bank == GM2_MELODIC_BANK || *bank == GM2_DRUM_BANK) {
+            SoundSet::Gm2
+        } else if banks.iter().any(|bank| [XG_SFX_VOICE_BANK, XG_SFX_KIT_BANK, XG_DRUM_BANK].contains(bank)) {
+            SoundSet::Xg
+        } else if banks.iter().any(|bank| *bank != 0) {
+            SoundSet::Gs
+        } else {
+            SoundSet::Gm1
+        }
+    }
+}
+
+// Families of 8 programs each as defined by General MIDI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InstrumentFamily {
+    Piano,
+    ChromaticPercussion,
+    Organ,
+    Guitar,
+    Bass,
+    Strings,
+    Ensemble,
+    Brass,
+    Reed,
+    Pipe,
+    SynthLead,
+    SynthPad,
+    SynthEffects,
+    Ethnic,
+    Percussive,
+    SoundEffects,
+    Drums, // Drum kits, where every key plays another instrument
+}
+
+const FAMILIES: [InstrumentFamily; 16] = [
+    InstrumentFamily::Piano, InstrumentFamily::ChromaticPercussion, InstrumentFamily::Organ, InstrumentFamily::Guitar,
+    InstrumentFamily::Bass, InstrumentFamily::Strings, InstrumentFamily::Ensemble, InstrumentFamily::Brass,
+    InstrumentFamily::Reed, InstrumentFamily::Pipe, InstrumentFamily::SynthLead, InstrumentFamily::SynthPad,
+    InstrumentFamily::SynthEffects, InstrumentFamily::Ethnic, InstrumentFamily::Percussive, InstrumentFamily::SoundEffects,
+];
+
+fn find_name(table: &[(u8, u8, &'static str)], program: u8, bank: u8) -> Option<&'static str> {
+    table.iter().find(|(p, b, _)| *p == program && *b == bank).map(|(_, _, name)| *name)
+}
+
+fn find_kit(table: &[(u8, &'static str)], program: u8) -> Option<&'static str> {
+    table.iter().find(|(p, _)| *p == program).map(|(_, name)| *name)
+}
+
+// Whether a channel plays a drum kit. Channel 10 is a drum channel unless a melodic bank is selected on it.
+fn is_drum(channel: u8, bank_msb: u8, set: SoundSet) -> bool {
+    let drum_channel = channel & 0xF == DRUM_CHANNEL;
+    match set {
+        SoundSet::Gm1 | SoundSet::Gs => drum_channel,
+        SoundSet::Gm2 => bank_msb == GM2_DRUM_BANK || (drum_channel && bank_msb != GM2_MELODIC_BANK),
+        SoundSet::Xg => bank_msb == XG_DRUM_BANK || bank_msb == XG_SFX_KIT_BANK || (drum_channel && bank_msb != XG_SFX_VOICE_BANK),
+    }
+}
+
+// Sound that a channel plays: the program together with the bank it was selected from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Patch {
+    pub bank_msb: u8,
+    pub bank_lsb: u8,
+    pub program: u8,
+    pub drum: bool,
+}
+
+impl Patch {
+    pub fn new(channel: u8, state: &ChannelState, set: SoundSet) -> Self {
+        let (bank_msb, bank_lsb) = state.bank;
+        Self { bank_msb, bank_lsb, program: state.program, drum: is_drum(channel, bank_msb, set) }
+    }
+
+    pub fn family(&self) -> InstrumentFamily {
+        if self.drum {
+            InstrumentFamily::Drums
+        } else {
+            FAMILIES[(self.program as usize & 0x7F) / 8]
+        }
+    }
+
+    // Unknown variations fall back to the sound of bank 0, like the synthesizers of these sets do
+    pub fn name(&self, set: SoundSet) -> &'static str {
+        let program = self.program & 0x7F;
+        let gm_name = GM1_NAMES[program as usize];
+        if self.drum {
+            let kit = match set {
+                SoundSet::Gm1 => None,
+                SoundSet::Gm2 | SoundSet::Gs => find_kit(DRUM_KITS, program),
+                SoundSet::Xg if self.bank_msb == XG_SFX_KIT_BANK => find_kit(XG_SFX_KITS, program),
+                SoundSet::Xg => find_kit(XG_DRUM_KITS, program),
+            };
+            return kit.unwrap_or("Standard Kit");
+        }
+        match set {
+            SoundSet::Gm1 => gm_name,
+            SoundSet::Gm2 => find_name(GM2_VARIATIONS, program, self.bank_lsb).unwrap_or(gm_name),
+            SoundSet::Gs => find_name(GS_VARIATIONS, program, self.bank_msb).unwrap_or(GS_CAPITAL_NAMES[program as usize]),
+            SoundSet::Xg if self.bank_msb == XG_SFX_VOICE_BANK => find_kit(XG_SFX_VOICES, program).unwrap_or(gm_name),
+            SoundSet::Xg => gm_name,
+        }
+    }
+}
+
+// Patches of all channels over the course of a file
+#[derive(Debug, Clone)]
+pub struct PatchMap {
+    pub sound_set: SoundSet,
+    changes: HashMap<ChannelId, Vec<(u64, Patch)>>, // Program changes and resets per channel in time order
+    note_patches: Vec<Vec<Patch>>, // Patch of every note on per track, in the order of the track
+}
+
+impl PatchMap {
+    pub fn new(file: &MidiFile) -> Self {
+        let sound_set = SoundSet::detect(file);
+        let mut ports = PortTracker::new(file);
+        let mut states = ChannelStates::new();
+        let mut changes: HashMap<ChannelId, Vec<(u64, Patch)>> = HashMap::new();
+        let mut note_patches = vec![Vec::new(); file.tracks.len()];
+        for (tick, track, event) in file.merged_events() {
+            let channel = ports.update(track, event);
+            states.update(ports.port(track), event);
+            match (channel, event) {
+                (Some(channel), Event::Midi(_, MidiEvent::NoteOn { velocity, .. })) if *velocity > 0 => {
+                    note_patches[track].push(Patch::new(channel.channel, states.get(channel), sound_set));
+                },
+                (Some(channel), Event::Midi(_, MidiEvent::ProgramChange(_))) => {
+                    let patch = Patch::new(channel.channel, states.get(channel), sound_set);
+                    changes.entry(channel).or_default().push((tick, patch));
+                },
+                (None, Event::Sysex(_)) => {
+                    // Resets return the channels of the port to their initial patch
+                    let port = ports.port(track);
+                    for (channel, channel_changes) in changes.iter_mut().filter(|(channel, _)| channel.port == port) {
+                        let patch = Patch::new(channel.channel, states.get(*channel), sound_set);
+                        if channel_changes.last().map(|(_, last)| *last) != Some(patch) {
+                            channel_changes.push((tick, patch));
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+        Self { sound_set, changes, note_patches }
+    }
+
+    // Patches of the note ons of a track in their order. Unlike `patch_at` this follows the order of the
+    // events, so program changes after a note on on the same tick do not apply to it.
+    pub fn note_patches(&self, track: usize) -> &[Patch] {
+        &self.note_patches[track]
+    }
+
+    // Patch that plays at `tick`, including program changes on the same tick
+    pub fn patch_at(&self, channel: ChannelId, tick: u64) -> Patch {
+        let initial = Patch::new(channel.channel, &ChannelState::new(), self.sound_set);
+        match self.changes.get(&channel) {
+            Some(changes) => {
+                let index = changes.partition_point(|(change_tick, _)| *change_tick <= tick);
+                changes[..index].last().map_or(initial, |(_, patch)| *patch)
+            },
+            None => initial
+        }
+    }
+
+    pub fn name_at(&self, channel: ChannelId, tick: u64) -> &'static str {
+        self.patch_at(channel, tick).name(self.sound_set)
+    }
+}
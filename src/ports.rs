@@ -0,0 +1,132 @@
+
+use std::fmt::Display;
+
+use crate::midi_parser::{MidiFile, Event, MetaEvent};
+
+pub const PORT_COUNT: usize = 128;
+pub const CHANNEL_COUNT: usize = PORT_COUNT * 16; // Channels that can be addressed over all ports
+
+// Files with more than 16 channels address them over several ports, so a channel is only
+// identified together with the port of its track
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct ChannelId {
+    pub port: u8,
+    pub channel: u8,
+}
+
+impl ChannelId {
+    pub fn new(port: u8, channel: u8) -> Self {
+        Self { port: port & 0x7F, channel: channel & 0xF }
+    }
+
+    // Index into tables with CHANNEL_COUNT entries
+    pub fn index(&self) -> usize {
+        (self.port as usize & 0x7F) * 16 + (self.channel as usize & 0xF)
+    }
+}
+
+impl Display for ChannelId {
+    // Counted from 1 like on devices, the port as a letter: A1 .. A16, B1 ..
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.port {
+            0..=25 => write!(f, "{}{}", (b'A' + self.port) as char, self.channel + 1),
+            _ => write!(f, "{}:{}", self.port + 1, self.channel + 1),
+        }
+    }
+}
+
+// Assigns ports to the tracks of a file. A track plays on port 0 until it contains a MIDI Port
+// event or a Device Name event. Device names get the free ports in the order they first appear:
+// port 0 and the ports of MIDI Port events are never given to a device, so that devices do not share
+// channels with other tracks. Only when all ports are taken the last one is shared.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PortMap {
+    devices: Vec<(String, u8)>, // Device name and its port
+}
+
+impl PortMap {
+    pub fn new(file: &MidiFile) -> Self {
+        let mut names = Vec::new();
+        let mut used = [false; PORT_COUNT];
+        used[0] = true;
+        for (_, _, event) in file.merged_events() {
+            match event {
+                Event::Meta(MetaEvent::MidiPort { port }) => used[*port as usize & 0x7F] = true,
+                Event::Meta(MetaEvent::DeviceName { text }) if !names.iter().any(|name| name == text.as_str()) => {
+                    names.push(text.to_string());
+                },
+                _ => {}
+            }
+        }
+        let mut free_ports = (0..PORT_COUNT).filter(|port| !used[*port]);
+        let devices = names.into_iter().map(|name| (name, free_ports.next().unwrap_or(PORT_COUNT - 1) as u8)).collect();
+        Self { devices }
+    }
+
+    // Port of a track after `event`, given the port before it
+    pub fn next_port(&self, port: u8, event: &Event) -> u8 {
+        match event {
+            Event::Meta(MetaEvent::MidiPort { port }) => *port & 0x7F,
+            Event::Meta(MetaEvent::DeviceName { text }) => {
+                self.devices.iter().find(|(name, _)| name == text.as_str()).map_or(port, |(_, port)| *port)
+            },
+            _ => port
+        }
+    }
+}
+
+// Follows the ports of all tracks while going through the merged events of a file
+#[derive(Debug, Clone)]
+pub struct PortTracker {
+    map: PortMap,
+    ports: Vec<u8>, // Current port per track
+}
+
+impl PortTracker {
+    pub fn new(file: &MidiFile) -> Self {
+        Self { map: PortMap::new(file), ports: vec![0; file.tracks.len()] }
+    }
+
+    pub fn port(&self, track: usize) -> u8 {
+        self.ports[track]
+    }
+
+    // Has to be called for every event in order. Returns the channel of channel events.
+    pub fn update(&mut self, track: usize, event: &Event) -> Option<ChannelId> {
+        let port = &mut self.ports[track];
+        *port = self.map.next_port(*port, event);
+        match event {
+            Event::Midi(channel, _) => Some(ChannelId::new(*port, *channel)),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{HeaderChunk, TrackChunk, Format, Division, MidiEvent};
+
+    #[test]
+    fn device_ports_do_not_collide() {
+        let note = Event::Midi(0, MidiEvent::NoteOn { key: 60, velocity: 100 });
+        let device = |name: &str| Event::Meta(MetaEvent::DeviceName { text: name.into() });
+        let tracks = vec![
+            vec![(0, note.clone())],
+            vec![(0, Event::Meta(MetaEvent::MidiPort { port: 1 })), (0, note.clone())],
+            vec![(0, device("Synth")), (0, note.clone())],
+            vec![(0, device("Sampler")), (0, note.clone())],
+            vec![(0, device("Synth")), (0, note.clone())],
+        ];
+        let header = HeaderChunk { format: Format::SimulTrack, ntrks: tracks.len() as u32, division: Division::TicksPerQuarter(96) };
+        let tracks = tracks.into_iter().map(|mut events| {
+            events.push((0, Event::Meta(MetaEvent::EndOfTrack)));
+            TrackChunk { events }
+        }).collect();
+        let file = MidiFile { header, tracks, riff_info: None };
+
+        let mut tracker = PortTracker::new(&file);
+        let channels = file.merged_events().filter_map(|(_, track, event)| tracker.update(track, event).map(|channel| (track, channel.port))).collect::<Vec<_>>();
+        assert_eq!(channels, [(0, 0), (1, 1), (2, 2), (3, 3), (4, 2)]);
+    }
+}
@@ -0,0 +1,229 @@
+
+use std::collections::VecDeque;
+
+use crate::midi_parser::{MidiFile, TrackChunk, Division, Event, MidiEvent, MetaEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantizeOptions {
+    pub grid: u32, // Note value of the grid, e.g. 16 for sixteenth notes or 12 for eighth triplets
+    pub strength: f64, // 0.0: unchanged, 1.0: exactly on the grid
+    pub swing: f64, // 0.0: straight, 1.0: every second grid point is delayed to the triplet position
+    pub quantize_ends: bool, // Otherwise notes keep their length
+}
+
+impl QuantizeOptions {
+    pub fn new(grid: u32) -> Self {
+        Self { grid, strength: 1.0, swing: 0.0, quantize_ends: false }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanizeOptions {
+    pub timing: u32, // Maximum shift of a note in ticks
+    pub velocity: u8, // Maximum change of the velocity
+    pub seed: u64,
+}
+
+// xorshift64*, so that humanized files can be reproduced from their seed
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        // splitmix64 spreads similar seeds and avoids the all zero state
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Self((z ^ (z >> 31)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // Uniform in -max..=max
+    fn jitter(&mut self, max: u32) -> i64 {
+        (self.next() % (2 * max as u64 + 1)) as i64 - max as i64
+    }
+}
+
+// Pairs the note ons of a track with their note offs (first in, first out). Returns the event indices.
+fn note_pairs(events: &[(u64, Event)]) -> Vec<(usize, Option<usize>)> {
+    let mut pairs: Vec<(usize, Option<usize>)> = Vec::new();
+    let mut open_notes = vec![VecDeque::<usize>::new(); 16 * 128];
+    for (index, (_, event)) in events.iter().enumerate() {
+        match event {
+            Event::Midi(channel, MidiEvent::NoteOff { key, .. }) | Event::Midi(channel, MidiEvent::NoteOn { key, velocity: 0 }) => {
+                if let Some(pair) = open_notes[(*channel as usize & 0xF) * 128 + (*key as usize & 0x7F)].pop_front() {
+                    pairs[pair].1 = Some(index);
+                }
+            },
+            Event::Midi(channel, MidiEvent::NoteOn { key, .. }) => {
+                open_notes[(*channel as usize & 0xF) * 128 + (*key as usize & 0x7F)].push_back(pairs.len());
+                pairs.push((index, None));
+            },
+            _ => {}
+        }
+    }
+    pairs
+}
+
+// Moves the notes of a track. `shift` gets the start and end tick of a note and returns the new ones.
+fn move_notes(track: &mut TrackChunk, mut shift: impl FnMut(u64, Option<u64>, &mut Event) -> (u64, Option<u64>)) {
+    let mut events = track.to_absolute(0);
+    let end_of_track = match events.last() {
+        Some((_, Event::Meta(MetaEvent::EndOfTrack))) => events.pop(),
+        _ => None
+    };
+    for (on, off) in note_pairs(&events) {
+        let (start, end) = shift(events[on].0, off.map(|off| events[off].0), &mut events[on].1);
+        events[on].0 = start;
+        if let (Some(off), Some(end)) = (off, end) {
+            events[off].0 = end;
+        }
+    }
+    // Stable sort: events on the same tick keep their order
+    events.sort_by_key(|(tick, _)| *tick);
+    // The End of Track event has to stay behind the moved notes
+    if let Some((tick, event)) = end_of_track {
+        let last_tick = events.last().map(|(last_tick, _)| *last_tick).unwrap_or(0);
+        events.push((tick.max(last_tick), event));
+    }
+    *track = TrackChunk::from_absolute(events);
+}
+
+fn nearest_grid_point(tick: u64, grid: f64, swing: f64) -> f64 {
+    let pair = (tick as f64 / (2.0 * grid)).floor() * 2.0 * grid;
+    let candidates = [pair, pair + grid + swing * grid / 3.0, pair + 2.0 * grid];
+    candidates.into_iter().min_by(|a, b| (a - tick as f64).abs().total_cmp(&(b - tick as f64).abs())).unwrap()
+}
+
+impl MidiFile {
+    // Only files with a musical division can be quantized
+    pub fn quantize(&mut self, options: &QuantizeOptions) {
+        let ticks_per_quarter = match self.header.division {
+            Division::TicksPerQuarter(ticks) => ticks,
+            Division::TicksPerFrame(..) => return,
+        };
+        if options.grid == 0 {
+            return;
+        }
+        let grid = ticks_per_quarter as f64 * 4.0 / options.grid as f64;
+        let strength = options.strength.clamp(0.0, 1.0);
+        let quantize = |tick: u64| {
+            let target = nearest_grid_point(tick, grid, options.swing.clamp(0.0, 1.0));
+            (tick as f64 + (target - tick as f64) * strength).round().max(0.0) as u64
+        };
+
+        for track in self.tracks.iter_mut() {
+            move_notes(track, |start, end, _| {
+                let new_start = quantize(start);
+                let new_end = end.map(|end| if options.quantize_ends {
+                    // Notes must not collapse to zero length
+                    quantize(end).max(new_start + 1)
+                } else {
+                    (end + new_start).saturating_sub(start)
+                });
+                (new_start, new_end)
+            });
+        }
+    }
+
+    pub fn humanize(&mut self, options: &HumanizeOptions) {
+        let mut random = Random::new(options.seed);
+        for track in self.tracks.iter_mut() {
+            move_notes(track, |start, end, event| {
+                if let Event::Midi(_, MidiEvent::NoteOn { velocity, .. }) = event {
+                    *velocity = (*velocity as i64 + random.jitter(options.velocity as u32)).clamp(1, 127) as u8;
+                }
+                let shift = random.jitter(options.timing);
+                let new_start = (start as i64 + shift).max(0) as u64;
+                (new_start, end.map(|end| (end + new_start).saturating_sub(start)))
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{HeaderChunk, Format};
+
+    // A file with one note per (start, end) pair on consecutive keys
+    fn file(notes: &[(u64, u64)]) -> MidiFile {
+        let mut events = Vec::new();
+        for (key, (start, end)) in notes.iter().enumerate() {
+            events.push((*start, Event::Midi(0, MidiEvent::NoteOn { key: 60 + key as u8, velocity: 100 })));
+            events.push((*end, Event::Midi(0, MidiEvent::NoteOff { key: 60 + key as u8, velocity: 64 })));
+        }
+        events.sort_by_key(|(tick, _)| *tick);
+        let end = events.last().map(|(tick, _)| *tick).unwrap_or(0);
+        events.push((end, Event::Meta(MetaEvent::EndOfTrack)));
+        let header = HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: Division::TicksPerQuarter(96) };
+        MidiFile { header, tracks: vec![TrackChunk::from_absolute(events)], riff_info: None }
+    }
+
+    // (start, end, velocity) of the notes, in the order of their note ons
+    fn notes(file: &MidiFile) -> Vec<(u64, u64, u8)> {
+        let events = file.tracks[0].to_absolute(0);
+        note_pairs(&events).into_iter().map(|(on, off)| {
+            let velocity = match events[on].1 {
+                Event::Midi(_, MidiEvent::NoteOn { velocity, .. }) => velocity,
+                _ => unreachable!()
+            };
+            (events[on].0, events[off.unwrap()].0, velocity)
+        }).collect()
+    }
+
+    fn quantized(notes_before: &[(u64, u64)], options: QuantizeOptions) -> Vec<(u64, u64)> {
+        let mut file = file(notes_before);
+        file.quantize(&options);
+        notes(&file).into_iter().map(|(start, end, _)| (start, end)).collect()
+    }
+
+    #[test]
+    fn snaps_to_the_grid() {
+        // Sixteenth notes are 24 ticks long at 96 ticks per quarter
+        let notes = [(10, 50), (30, 60), (100, 110)];
+        assert_eq!(quantized(&notes, QuantizeOptions::new(16)), [(0, 40), (24, 54), (96, 106)]);
+        let half_strength = QuantizeOptions { strength: 0.5, ..QuantizeOptions::new(16) };
+        assert_eq!(quantized(&notes, half_strength), [(5, 45), (27, 57), (98, 108)]);
+    }
+
+    #[test]
+    fn swing_delays_the_off_beats() {
+        // Eighth notes are 48 ticks long, full swing moves the off-beat to the triplet position at 64
+        let notes = [(2, 20), (50, 60), (94, 100)];
+        let swing = |swing| QuantizeOptions { swing, ..QuantizeOptions::new(8) };
+        assert_eq!(quantized(&notes, swing(0.0)), [(0, 18), (48, 58), (96, 102)]);
+        assert_eq!(quantized(&notes, swing(1.0)), [(0, 18), (64, 74), (96, 102)]);
+        assert_eq!(quantized(&notes, swing(0.5)), [(0, 18), (56, 66), (96, 102)]);
+    }
+
+    #[test]
+    fn quantized_ends_keep_a_length_of_one_tick() {
+        let options = QuantizeOptions { quantize_ends: true, ..QuantizeOptions::new(16) };
+        assert_eq!(quantized(&[(2, 8), (20, 40)], options), [(0, 1), (24, 48)]);
+    }
+
+    #[test]
+    fn humanize_is_reproducible() {
+        let original = file(&[(0, 96), (96, 192), (192, 288), (288, 384)]);
+        let humanized = |seed| {
+            let mut file = original.clone();
+            file.humanize(&HumanizeOptions { timing: 10, velocity: 20, seed });
+            notes(&file)
+        };
+        let humanized_notes = humanized(7);
+        assert_eq!(humanized_notes, [(0, 96, 100), (98, 194, 118), (199, 295, 111), (286, 382, 116)]);
+        assert_eq!(humanized_notes, humanized(7));
+        assert_ne!(humanized_notes, humanized(8));
+        for ((start, end, velocity), (original_start, original_end, _)) in humanized_notes.iter().zip(notes(&original)) {
+            assert!(start.abs_diff(original_start) <= 10);
+            assert_eq!(end - start, original_end - original_start);
+            assert!((80..=120).contains(velocity));
+        }
+    }
+}
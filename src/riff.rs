@@ -0,0 +1,103 @@
+
+use crate::midi_parser::{MidiError, MidiErrorType, Diagnostic, DiagnosticKind};
+use crate::text_encoding::{MetaText, TextEncoding};
+
+// Metadata stored in the LIST/INFO chunk of a RIFF container
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RiffInfo {
+    pub title: Option<String>,     // INAM
+    pub artist: Option<String>,    // IART
+    pub copyright: Option<String>, // ICOP
+    pub comment: Option<String>,   // ICMT
+    pub date: Option<String>,      // ICRD
+    pub genre: Option<String>,     // IGNR
+    pub software: Option<String>,  // ISFT
+    pub other: Vec<(String, String)>
+}
+
+impl RiffInfo {
+    fn set(&mut self, id: &[u8], value: String) {
+        match id {
+            b"INAM" => self.title = Some(value),
+            b"IART" => self.artist = Some(value),
+            b"ICOP" => self.copyright = Some(value),
+            b"ICMT" => self.comment = Some(value),
+            b"ICRD" => self.date = Some(value),
+            b"IGNR" => self.genre = Some(value),
+            b"ISFT" => self.software = Some(value),
+            _ => self.other.push((String::from_utf8_lossy(id).to_string(), value))
+        }
+    }
+}
+
+pub fn is_riff(input: &[u8]) -> bool {
+    input.starts_with(b"RIFF")
+}
+
+// Splits `input` into (id, data, offset, complete) tuples. Chunks are padded to an even length.
+// A chunk that claims more bytes than available is cut off at the end of the input.
+fn sub_chunks(input: &[u8], base_offset: usize) -> Vec<(&[u8], &[u8], usize, bool)> {
+    let mut chunks = vec![];
+    let mut position = 0;
+    while position + 8 <= input.len() {
+        let id = &input[position..position+4];
+        let length = u32::from_le_bytes(input[position+4..position+8].try_into().unwrap()) as usize;
+        let start = position + 8;
+        let complete = start + length <= input.len();
+        let end = if complete { start + length } else { input.len() };
+        chunks.push((id, &input[start..end], base_offset + position, complete));
+        position = end + length % 2;
+    }
+    chunks
+}
+
+fn read_info(list: &[u8]) -> RiffInfo {
+    let mut info = RiffInfo::default();
+    for (id, data, _, _) in sub_chunks(list, 0) {
+        let text = data.split(|b| *b == 0).next().unwrap_or(&[]);
+        info.set(id, MetaText::decode(text.to_vec(), TextEncoding::Auto).as_str().trim().to_string());
+    }
+    info
+}
+
+// Locates the standard midi file inside a RIFF RMID container. Returns the embedded data, its
+// offset inside `input` and the INFO metadata if present.
+pub fn unwrap_rmid<'a>(input: &'a [u8], lenient: bool, diagnostics: &mut Vec<Diagnostic>) -> Result<(&'a [u8], usize, Option<RiffInfo>), MidiError> {
+    if input.len() < 12 || &input[8..12] != b"RMID" {
+        return Err(MidiError::new(MidiErrorType::MissingHeader, "RIFF container does not hold midi data (form type RMID expected)").at_offset(8));
+    }
+    let declared_length = u32::from_le_bytes(input[4..8].try_into().unwrap()) as usize;
+    let mut content_end = 8 + declared_length;
+    if content_end > input.len() {
+        if !lenient {
+            return Err(MidiError::new(MidiErrorType::TruncatedChunk, format!("RIFF container is shorter than its declared length of {} bytes", declared_length)).at_offset(0));
+        }
+        diagnostics.push(Diagnostic { kind: DiagnosticKind::WrongChunkLength, message: format!("RIFF container declares {} bytes but only {} are present", declared_length, input.len() - 8), offset: Some(0), track: None });
+        content_end = input.len();
+    }
+
+    let mut data = None;
+    let mut info = None;
+    for (id, chunk_data, offset, complete) in sub_chunks(&input[12..content_end], 12) {
+        match id {
+            b"data" => {
+                if !complete {
+                    if !lenient {
+                        return Err(MidiError::new(MidiErrorType::TruncatedChunk, "RIFF data chunk is shorter than its declared length").at_offset(offset as u64));
+                    }
+                    diagnostics.push(Diagnostic { kind: DiagnosticKind::WrongChunkLength, message: String::from("RIFF data chunk is shorter than its declared length"), offset: Some(offset as u64), track: None });
+                }
+                data = Some((chunk_data, offset + 8));
+            },
+            b"LIST" if chunk_data.starts_with(b"INFO") => {
+                info = Some(read_info(&chunk_data[4..]));
+            },
+            _ => {}
+        }
+    }
+
+    match data {
+        Some((data, data_offset)) => Ok((data, data_offset, info)),
+        None => Err(MidiError::new(MidiErrorType::MissingHeader, "RIFF container has no data chunk").at_offset(12))
+    }
+}
@@ -0,0 +1,128 @@
+
+use crate::midi_parser::{MidiFile, Format, Division, Event, MetaEvent};
+use crate::timecode::frames_per_second;
+
+pub const DEFAULT_TEMPO: u32 = 500_000; // Microseconds per quarter note (120 BPM)
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TempoSegment {
+    tick: u64,
+    tempo: u32,
+    seconds: f64, // Time at which the segment starts
+}
+
+// Converts between absolute ticks and seconds. Absolute ticks count from the start of the file,
+// for format 2 files the patterns are laid out one after another (see `MidiFile::get_track_start_ticks`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap {
+    division: Division,
+    segments: Vec<TempoSegment>,
+    track_starts: Vec<u64>,
+}
+
+impl TempoMap {
+    pub fn new(file: &MidiFile) -> Self {
+        let mut changes = vec![(0, DEFAULT_TEMPO)];
+        let track_starts = file.get_track_start_ticks();
+
+        for (track, start) in file.tracks.iter().zip(track_starts.iter()) {
+            if file.header.format == Format::SequenceTrack {
+                // Every pattern has its own tempo, starting with the default
+                changes.push((*start, DEFAULT_TEMPO));
+            }
+
+            let mut tick = *start;
+            for (dt, event) in track.events.iter() {
+                tick += *dt as u64;
+                if let Event::Meta(MetaEvent::SetTempo { tempo }) = event {
+                    changes.push((tick, *tempo));
+                }
+            }
+        }
+
+        // Stable sort: of several changes on the same tick the last one wins
+        changes.sort_by_key(|(tick, _)| *tick);
+
+        let mut map = Self { division: file.header.division, segments: Vec::with_capacity(changes.len()), track_starts };
+        for (tick, tempo) in changes {
+            let seconds = match map.segments.last() {
+                Some(last) => last.seconds + (tick - last.tick) as f64 * map.seconds_per_tick(last.tempo),
+                None => 0.0
+            };
+            map.segments.push(TempoSegment { tick, tempo, seconds });
+        }
+        map
+    }
+
+    // Tempo events are ignored for SMPTE timed files
+    fn seconds_per_tick(&self, tempo: u32) -> f64 {
+        match self.division {
+            Division::TicksPerQuarter(ticks) => tempo as f64 / 1_000_000.0 / ticks as f64,
+            Division::TicksPerFrame(fps, ticks) => 1.0 / (frames_per_second(fps) * ticks as f64),
+        }
+    }
+
+    fn segment_at(&self, tick: u64) -> &TempoSegment {
+        let index = self.segments.partition_point(|segment| segment.tick <= tick);
+        &self.segments[index.saturating_sub(1)]
+    }
+
+    // Absolute tick at which a track starts
+    pub fn track_start(&self, track: usize) -> u64 {
+        self.track_starts.get(track).copied().unwrap_or(0)
+    }
+
+    pub fn tempo_at(&self, tick: u64) -> u32 {
+        self.segment_at(tick).tempo
+    }
+
+    pub fn ticks_to_seconds(&self, tick: u64) -> f64 {
+        let segment = self.segment_at(tick);
+        segment.seconds + (tick - segment.tick) as f64 * self.seconds_per_tick(segment.tempo)
+    }
+
+    // Tick closest to the time. Times before the start of the file map to tick 0.
+    pub fn seconds_to_ticks(&self, seconds: f64) -> u64 {
+        let index = self.segments.partition_point(|segment| segment.seconds <= seconds);
+        let segment = &self.segments[index.saturating_sub(1)];
+        segment.tick + ((seconds - segment.seconds).max(0.0) / self.seconds_per_tick(segment.tempo)).round() as u64
+    }
+
+    // First tick that does not lie before the time. Playback that starts at `seconds` starts with the events of this tick.
+    pub fn first_tick_at(&self, seconds: f64) -> u64 {
+        let tick = self.seconds_to_ticks(seconds);
+        if self.ticks_to_seconds(tick) < seconds { tick + 1 } else { tick }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::{HeaderChunk, TrackChunk};
+
+    fn file(events: Vec<(u32, Event)>) -> MidiFile {
+        let header = HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: Division::TicksPerQuarter(480) };
+        MidiFile { header, tracks: vec![TrackChunk { events }], riff_info: None }
+    }
+
+    #[test]
+    fn default_tempo_is_120_bpm() {
+        let map = TempoMap::new(&file(vec![(0, Event::Meta(MetaEvent::EndOfTrack))]));
+        assert_eq!(map.ticks_to_seconds(480), 0.5);
+        assert_eq!(map.seconds_to_ticks(0.5), 480);
+        assert_eq!(map.seconds_to_ticks(-1.0), 0);
+    }
+
+    #[test]
+    fn conversions_are_inverse() {
+        let map = TempoMap::new(&file(vec![
+            (960, Event::Meta(MetaEvent::SetTempo { tempo: 250_000 })),
+            (333, Event::Meta(MetaEvent::SetTempo { tempo: 1_000_003 })),
+            (0, Event::Meta(MetaEvent::EndOfTrack)),
+        ]));
+        assert_eq!(map.ticks_to_seconds(1200), 1.125);
+        for tick in (0..5000).step_by(7) {
+            assert_eq!(map.seconds_to_ticks(map.ticks_to_seconds(tick)), tick);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::shift_jis_table::SHIFT_JIS_ROWS;
+
+// Encoding used for meta event texts that are not valid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8, // Invalid sequences are replaced with U+FFFD
+    Latin1,
+    Windows1252,
+    ShiftJis, // Windows code page 932
+    Auto, // Shift-JIS if the text looks like Japanese, Windows-1252 otherwise
+}
+
+impl FromStr for TextEncoding {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(Self::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Ok(Self::Latin1),
+            "windows-1252" | "cp1252" => Ok(Self::Windows1252),
+            "shift-jis" | "sjis" | "cp932" => Ok(Self::ShiftJis),
+            "auto" => Ok(Self::Auto),
+            _ => Err(())
+        }
+    }
+}
+
+// Text of a meta event. The raw bytes are kept, so that files are written back unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetaText {
+    raw: Vec<u8>,
+    text: String,
+}
+
+impl MetaText {
+    // Decodes the bytes as UTF-8 and falls back to `fallback` if that fails
+    pub fn decode(raw: Vec<u8>, fallback: TextEncoding) -> Self {
+        let text = match String::from_utf8(raw.clone()) {
+            Ok(text) => text,
+            Err(_) => decode_with(&raw, fallback),
+        };
+        Self { raw, text }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+}
+
+impl From<&str> for MetaText {
+    fn from(text: &str) -> Self {
+        Self { raw: text.as_bytes().to_vec(), text: text.to_string() }
+    }
+}
+
+impl From<String> for MetaText {
+    fn from(text: String) -> Self {
+        Self { raw: text.as_bytes().to_vec(), text }
+    }
+}
+
+impl Display for MetaText {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+// Characters of Windows-1252 for 0x80..=0x9F. The undefined codes map to the C1 controls like Latin-1.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+fn decode_latin1(raw: &[u8]) -> String {
+    raw.iter().map(|byte| *byte as char).collect()
+}
+
+fn decode_windows_1252(raw: &[u8]) -> String {
+    raw.iter().map(|byte| match byte {
+        0x80..=0x9F => WINDOWS_1252_HIGH[*byte as usize - 0x80],
+        _ => *byte as char
+    }).collect()
+}
+
+fn shift_jis_double(lead: u8, trail: u8) -> Option<char> {
+    if !(0x40..=0xFC).contains(&trail) || trail == 0x7F {
+        return None;
+    }
+    let index = trail as usize - 0x40 - (trail > 0x7F) as usize;
+    let row = SHIFT_JIS_ROWS.iter().find(|(row_lead, _)| *row_lead == lead)?.1;
+    row.chars().nth(index).filter(|c| *c != char::REPLACEMENT_CHARACTER)
+}
+
+// Returns the text and the number of double byte characters, or None if the bytes are not valid Shift-JIS
+fn try_decode_shift_jis(raw: &[u8]) -> Option<(String, usize)> {
+    let mut text = String::with_capacity(raw.len());
+    let mut double_byte_count = 0;
+    let mut bytes = raw.iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            0x00..=0x7F => text.push(*byte as char),
+            0xA1..=0xDF => text.push(char::from_u32(0xFF61 + (*byte as u32 - 0xA1)).unwrap()), // Half width katakana
+            0x81..=0x9F | 0xE0..=0xFC => {
+                text.push(shift_jis_double(*byte, *bytes.next()?)?);
+                double_byte_count += 1;
+            },
+            _ => return None
+        }
+    }
+    Some((text, double_byte_count))
+}
+
+fn decode_shift_jis(raw: &[u8]) -> String {
+    match try_decode_shift_jis(raw) {
+        Some((text, _)) => text,
+        // Decode what is possible and replace the rest
+        None => {
+            let mut text = String::with_capacity(raw.len());
+            let mut position = 0;
+            while position < raw.len() {
+                let byte = raw[position];
+                let (decoded, length) = match byte {
+                    0x00..=0x7F => (Some(byte as char), 1),
+                    0xA1..=0xDF => (char::from_u32(0xFF61 + (byte as u32 - 0xA1)), 1),
+                    0x81..=0x9F | 0xE0..=0xFC if position + 1 < raw.len() => match shift_jis_double(byte, raw[position + 1]) {
+                        Some(c) => (Some(c), 2),
+                        None => (None, 1)
+                    },
+                    _ => (None, 1)
+                };
+                text.push(decoded.unwrap_or(char::REPLACEMENT_CHARACTER));
+                position += length;
+            }
+            text
+        }
+    }
+}
+
+fn decode_with(raw: &[u8], encoding: TextEncoding) -> String {
+    match encoding {
+        TextEncoding::Utf8 => String::from_utf8_lossy(raw).to_string(),
+        TextEncoding::Latin1 => decode_latin1(raw),
+        TextEncoding::Windows1252 => decode_windows_1252(raw),
+        TextEncoding::ShiftJis => decode_shift_jis(raw),
+        TextEncoding::Auto => {
+            // Latin-1 letters mostly fall into the half width katakana range or form invalid
+            // Shift-JIS sequences, so Japanese text needs at least one double byte character
+            match try_decode_shift_jis(raw) {
+                Some((text, double_byte_count)) if double_byte_count > 0 => text,
+                _ => decode_windows_1252(raw),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(raw: &[u8], fallback: TextEncoding) -> String {
+        MetaText::decode(raw.to_vec(), fallback).as_str().to_string()
+    }
+
+    #[test]
+    fn utf8_is_tried_first() {
+        assert_eq!(decode("Grüße".as_bytes(), TextEncoding::ShiftJis), "Grüße");
+        assert_eq!(decode(b"Gr\xFC\xDFe", TextEncoding::Utf8), "Gr\u{FFFD}\u{FFFD}e");
+    }
+
+    #[test]
+    fn windows_1252_and_latin1() {
+        let raw = b"\x93Caf\xE9\x94 \x80";
+        assert_eq!(decode(raw, TextEncoding::Windows1252), "“Café” €");
+        assert_eq!(decode(raw, TextEncoding::Latin1), "\u{93}Café\u{94} \u{80}");
+    }
+
+    #[test]
+    fn shift_jis() {
+        // 日本語 and half width katakana ｱｲ
+        let raw = b"\x93\xFA\x96\x7B\x8C\xEA \xB1\xB2";
+        assert_eq!(decode(raw, TextEncoding::ShiftJis), "日本語 ｱｲ");
+        // A lead byte without a valid trail byte
+        assert_eq!(decode(b"a\x81", TextEncoding::ShiftJis), "a\u{FFFD}");
+    }
+
+    #[test]
+    fn auto_detection() {
+        assert_eq!(decode(b"\x82\xA0\x82\xA2", TextEncoding::Auto), "あい");
+        assert_eq!(decode(b"Caf\xE9 cr\xE8me", TextEncoding::Auto), "Café crème");
+        // Valid Shift-JIS, but only half width katakana, which is more likely Windows-1252
+        assert_eq!(decode(b"\xC0 la carte", TextEncoding::Auto), "À la carte");
+    }
+
+    #[test]
+    fn encoding_names() {
+        assert_eq!("Shift-JIS".parse(), Ok(TextEncoding::ShiftJis));
+        assert_eq!("cp1252".parse(), Ok(TextEncoding::Windows1252));
+        assert_eq!("latin1".parse(), Ok(TextEncoding::Latin1));
+        assert_eq!("ebcdic".parse::<TextEncoding>(), Err(()));
+    }
+}
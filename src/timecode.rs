@@ -0,0 +1,56 @@
+
+use std::fmt::Display;
+
+// Actual number of frames per second for a SMPTE frame rate. 29 stands for 29.97 drop-frame.
+pub fn frames_per_second(fps: u32) -> f64 {
+    match fps {
+        29 => 30_000.0 / 1001.0,
+        _ => fps as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timecode {
+    pub hours: u32,
+    pub minutes: u32,
+    pub seconds: u32,
+    pub frames: u32,
+    pub drop_frame: bool
+}
+
+impl Timecode {
+    pub fn from_seconds(time: f64, fps: u32) -> Self {
+        let mut frame_count = (time.max(0.0) * frames_per_second(fps)).floor() as u64;
+        let drop_frame = fps == 29;
+        let nominal_fps = if drop_frame { 30 } else { fps as u64 };
+
+        if drop_frame {
+            // Frame numbers 0 and 1 are skipped every minute, except for every tenth minute
+            const FRAMES_PER_10_MINUTES: u64 = 17982;
+            const FRAMES_PER_MINUTE: u64 = 1798;
+            let tens = frame_count / FRAMES_PER_10_MINUTES;
+            let rest = frame_count % FRAMES_PER_10_MINUTES;
+            frame_count += 18 * tens;
+            if rest >= 2 {
+                frame_count += 2 * ((rest - 2) / FRAMES_PER_MINUTE);
+            }
+        }
+
+        let total_seconds = frame_count / nominal_fps;
+        Self {
+            hours: (total_seconds / 3600) as u32,
+            minutes: (total_seconds / 60 % 60) as u32,
+            seconds: (total_seconds % 60) as u32,
+            frames: (frame_count % nominal_fps) as u32,
+            drop_frame
+        }
+    }
+}
+
+impl Display for Timecode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Drop-frame timecode is conventionally separated by a semicolon
+        let separator = if self.drop_frame { ';' } else { ':' };
+        write!(f, "{:02}:{:02}:{:02}{}{:02}", self.hours, self.minutes, self.seconds, separator, self.frames)
+    }
+}
@@ -0,0 +1,238 @@
+
+use crate::midi_parser::{MidiFile, TrackChunk, Format, Division, Event, MidiEvent, MetaEvent};
+use crate::tempo_map::DEFAULT_TEMPO;
+use crate::quantize::{QuantizeOptions, HumanizeOptions};
+
+pub const DRUM_CHANNEL: u8 = 9; // Channel 10 when counting from 1
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackSelector {
+    Index(usize),
+    Name(String), // Matches the Sequence/Track Name meta event
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    Transpose(i8), // Semitones. The drum channel is left alone, notes that leave the key range are dropped.
+    ScaleTempo(f64), // Playback speed factor, applied to the tempo events or to the ticks of SMPTE timed files
+    TimeStretch(f64), // Factor applied to the tick positions of all events
+    ScaleVelocity(f64),
+    CompressVelocity { threshold: u8, ratio: f64 }, // Velocities above the threshold are divided by the ratio
+    RemapChannel { from: u8, to: u8 }, // Mapping several channels to the same one merges them
+    DropTracks(Vec<TrackSelector>),
+    Quantize(QuantizeOptions),
+    Humanize(HumanizeOptions),
+}
+
+// Removes events while keeping the timing of the remaining ones
+fn retain_events(track: &mut TrackChunk, mut keep: impl FnMut(&Event) -> bool) {
+    let mut carried = 0;
+    let mut events = Vec::with_capacity(track.events.len());
+    for (dt, event) in track.events.drain(..) {
+        if keep(&event) {
+            events.push((dt + carried, event));
+            carried = 0;
+        } else {
+            carried += dt;
+        }
+    }
+    track.events = events;
+}
+
+fn track_name(track: &TrackChunk) -> Option<&str> {
+    track.events.iter().find_map(|(_, event)| match event {
+        Event::Meta(MetaEvent::SequenceTrackName { text }) => Some(text.as_str()),
+        _ => None
+    })
+}
+
+// Scaling factors have to be positive numbers. NaN would turn tempos, ticks and velocities into 0.
+pub fn is_valid_factor(factor: f64) -> bool {
+    factor.is_finite() && factor > 0.0
+}
+
+// Whether a tempo event is at the start of the track
+fn has_initial_tempo(track: &TrackChunk) -> bool {
+    track.events.iter().take_while(|(dt, _)| *dt == 0).any(|(_, event)| matches!(event, Event::Meta(MetaEvent::SetTempo { .. })))
+}
+
+fn scale_velocity(velocity: u8, scale: impl Fn(f64) -> f64) -> u8 {
+    // Velocity 0 would turn a note on into a note off
+    scale(velocity as f64).round().clamp(1.0, 127.0) as u8
+}
+
+impl MidiFile {
+    pub fn apply(&mut self, transform: &Transform) {
+        match transform {
+            Transform::Transpose(semitones) => {
+                for track in self.tracks.iter_mut() {
+                    retain_events(track, |event| match event {
+                        Event::Midi(channel, _) if *channel == DRUM_CHANNEL => true,
+                        Event::Midi(_, MidiEvent::NoteOn { key, .. }) | Event::Midi(_, MidiEvent::NoteOff { key, .. }) | Event::Midi(_, MidiEvent::PolyphonicKeyPressure { key, .. }) => {
+                            (0..=127).contains(&(*key as i16 + *semitones as i16))
+                        },
+                        _ => true
+                    });
+                    for (_, event) in track.events.iter_mut() {
+                        match event {
+                            Event::Midi(channel, _) if *channel == DRUM_CHANNEL => {},
+                            Event::Midi(_, MidiEvent::NoteOn { key, .. }) | Event::Midi(_, MidiEvent::NoteOff { key, .. }) | Event::Midi(_, MidiEvent::PolyphonicKeyPressure { key, .. }) => {
+                                *key = (*key as i16 + *semitones as i16) as u8;
+                            },
+                            _ => {}
+                        }
+                    }
+                }
+            },
+            Transform::ScaleTempo(factor) => {
+                if !is_valid_factor(*factor) {
+                    return;
+                }
+                // Ticks of SMPTE timed files have a fixed length that tempo events do not change
+                if matches!(self.header.division, Division::TicksPerFrame(..)) {
+                    self.apply(&Transform::TimeStretch(1.0 / factor));
+                    return;
+                }
+                let scale = |tempo: u32| (tempo as f64 / factor).round().clamp(1.0, 0xFF_FFFF as f64) as u32;
+                for track in self.tracks.iter_mut() {
+                    for (_, event) in track.events.iter_mut() {
+                        if let Event::Meta(MetaEvent::SetTempo { tempo }) = event {
+                            *tempo = scale(*tempo);
+                        }
+                    }
+                }
+                // Parts that start without a tempo event play at the default tempo, which has to be scaled as well
+                let default_tempo = (0, Event::Meta(MetaEvent::SetTempo { tempo: scale(DEFAULT_TEMPO) }));
+                if self.header.format == Format::SequenceTrack {
+                    for track in self.tracks.iter_mut().filter(|track| !has_initial_tempo(track)) {
+                        track.events.insert(0, default_tempo.clone());
+                    }
+                } else if !self.tracks.iter().any(has_initial_tempo) {
+                    if let Some(track) = self.tracks.first_mut() {
+                        track.events.insert(0, default_tempo);
+                    }
+                }
+            },
+            Transform::TimeStretch(factor) => {
+                if !is_valid_factor(*factor) {
+                    return;
+                }
+                // Absolute positions are scaled so that rounding errors do not add up
+                for track in self.tracks.iter_mut() {
+                    let events = track.to_absolute(0).into_iter()
+                        .map(|(tick, event)| ((tick as f64 * factor).round() as u64, event))
+                        .collect();
+                    *track = TrackChunk::from_absolute(events);
+                }
+            },
+            Transform::ScaleVelocity(factor) => {
+                if !is_valid_factor(*factor) {
+                    return;
+                }
+                self.map_velocities(|velocity| scale_velocity(velocity, |v| v * factor));
+            },
+            Transform::CompressVelocity { threshold, ratio } => {
+                if !is_valid_factor(*ratio) {
+                    return;
+                }
+                let threshold = *threshold as f64;
+                self.map_velocities(|velocity| scale_velocity(velocity, |v| if v > threshold { threshold + (v - threshold) / ratio } else { v }));
+            },
+            Transform::RemapChannel { from, to } => {
+                for track in self.tracks.iter_mut() {
+                    for (_, event) in track.events.iter_mut() {
+                        match event {
+                            Event::Midi(channel, _) | Event::Meta(MetaEvent::MidiChannelPrefix { channel }) if *channel == *from => {
+                                *channel = *to & 0xF;
+                            },
+                            _ => {}
+                        }
+                    }
+                }
+            },
+            Transform::DropTracks(selectors) => {
+                let mut index = 0;
+                self.tracks.retain(|track| {
+                    let selected = selectors.iter().any(|selector| match selector {
+                        TrackSelector::Index(i) => *i == index,
+                        TrackSelector::Name(name) => track_name(track) == Some(name.as_str()),
+                    });
+                    index += 1;
+                    !selected
+                });
+                self.header.ntrks = self.tracks.len() as u32;
+            },
+            Transform::Quantize(options) => self.quantize(options),
+            Transform::Humanize(options) => self.humanize(options),
+        }
+    }
+
+    // Velocities of note ons. Note offs and note ons with velocity 0 are left alone.
+    fn map_velocities(&mut self, map: impl Fn(u8) -> u8) {
+        for track in self.tracks.iter_mut() {
+            for (_, event) in track.events.iter_mut() {
+                if let Event::Midi(_, MidiEvent::NoteOn { velocity, .. }) = event {
+                    if *velocity != 0 {
+                        *velocity = map(*velocity);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_parser::HeaderChunk;
+
+    fn file(events: Vec<(u32, Event)>) -> MidiFile {
+        let header = HeaderChunk { format: Format::SingleTrack, ntrks: 1, division: Division::TicksPerQuarter(96) };
+        MidiFile { header, tracks: vec![TrackChunk { events }], riff_info: None }
+    }
+
+    fn tempos(file: &MidiFile) -> Vec<(u32, u32)> {
+        file.tracks[0].events.iter().filter_map(|(dt, event)| match event {
+            Event::Meta(MetaEvent::SetTempo { tempo }) => Some((*dt, *tempo)),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn invalid_factors_are_ignored() {
+        let original = file(vec![
+            (0, Event::Midi(0, MidiEvent::NoteOn { key: 60, velocity: 100 })),
+            (96, Event::Meta(MetaEvent::SetTempo { tempo: 400_000 })),
+            (0, Event::Meta(MetaEvent::EndOfTrack)),
+        ]);
+        for factor in [f64::NAN, f64::INFINITY, 0.0, -1.0] {
+            for transform in [Transform::ScaleTempo(factor), Transform::TimeStretch(factor), Transform::ScaleVelocity(factor), Transform::CompressVelocity { threshold: 64, ratio: factor }] {
+                let mut transformed = original.clone();
+                transformed.apply(&transform);
+                assert_eq!(transformed, original);
+            }
+        }
+    }
+
+    #[test]
+    fn scale_tempo_inserts_the_default_tempo_once() {
+        let mut file = file(vec![(96, Event::Meta(MetaEvent::SetTempo { tempo: 400_000 })), (0, Event::Meta(MetaEvent::EndOfTrack))]);
+        file.apply(&Transform::ScaleTempo(2.0));
+        assert_eq!(tempos(&file), [(0, 250_000), (96, 200_000)]);
+        file.apply(&Transform::ScaleTempo(2.0));
+        assert_eq!(tempos(&file), [(0, 125_000), (96, 100_000)]);
+    }
+
+    #[test]
+    fn scale_tempo_stretches_smpte_timed_files() {
+        let mut file = file(vec![
+            (0, Event::Midi(0, MidiEvent::NoteOn { key: 60, velocity: 100 })),
+            (80, Event::Midi(0, MidiEvent::NoteOff { key: 60, velocity: 64 })),
+            (0, Event::Meta(MetaEvent::EndOfTrack)),
+        ]);
+        file.header.division = Division::TicksPerFrame(25, 40);
+        file.apply(&Transform::ScaleTempo(2.0));
+        assert_eq!(file.tracks[0].events.iter().map(|(dt, _)| *dt).collect::<Vec<_>>(), [0, 40, 0]);
+        assert_eq!(tempos(&file), []);
+    }
+}